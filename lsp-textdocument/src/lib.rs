@@ -71,8 +71,8 @@ impl TextDocument {
                     &self.content[end..]
                 );
 
-                let start_line = range.start.line.max(0) as usize;
-                let end_line = range.end.line.max(0) as usize;
+                let start_line = range.start.line as usize;
+                let end_line = range.end.line as usize;
                 let added_offsets = compute_line_offsets(&change.text, false, start);
                 let added_offsets_len = added_offsets.len();
                 if end_line - start_line == added_offsets_len {
@@ -109,19 +109,19 @@ impl TextDocument {
             } else if start_off > last_modified_off {
                 spans.push(self.content[last_modified_off..start_off].to_string());
             }
-            if e.new_text.len() > 0 {
+            if !e.new_text.is_empty() {
                 spans.push(e.new_text);
             }
             last_modified_off = self.offset_at(e.range.end);
         }
         spans.push(self.content[last_modified_off..].to_string());
-        return Ok(())
+        Ok(())
     }
 
     pub fn position_at(&self, mut offset: usize) -> Position {
-        offset = offset.max(0).min(self.content.len());
+        offset = offset.min(self.content.len());
 
-        if self.line_offsets.len() == 0 {
+        if self.line_offsets.is_empty() {
             Position {
                 line: 0,
                 character: offset as u64,
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, process};
+
+use lsp_types::{Diagnostic, DiagnosticSeverity};
+use octave_parser::config;
+use octave_parser::diagnostics::{get_diagnostics, LintConfig, Rule};
+use octave_parser::parser::parse;
+
+use report::{FileReport, OutputFormat};
+
+mod report;
+
+const USAGE: &str = "Usage: octave-lint [--format human|json|sarif] [PATH]...
+
+Reports the diagnostics of the given Octave files, and of the .m files in the given directories
+or in the current one. Exits with status 1 when there are errors.
+
+The level of each rule can be set in a .octave-lint.toml file, in the directory of the files or
+in one of its parents:
+
+    [rules]
+    unused-variable = \"off\"
+    missing-semicolon-in-function = \"warning\"
+
+Levels are off, hint, info, warning and error. Diagnostics can also be suppressed on a line with
+a %#ok<RULE,...> comment, or %#ok for all rules. The rules, with their default level, are:
+";
+
+fn main() {
+    let mut format = OutputFormat::Human;
+    let mut paths = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let name = match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                for rule in Rule::ALL.iter() {
+                    println!("    {:32}{}", rule.code(), rule.default_level().name());
+                }
+                return;
+            }
+            "-f" | "--format" => args.next(),
+            _ => arg.strip_prefix("--format=").map(Into::into),
+        };
+        match name {
+            Some(name) => match OutputFormat::from_name(&name) {
+                Some(f) => format = f,
+                None => fail(&format!("unknown format {}\n\n{}", name, USAGE)),
+            },
+            None if arg.starts_with('-') => fail(&format!("unknown option {}\n\n{}", arg, USAGE)),
+            None => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        paths.push(".".into());
+    }
+
+    let mut files = vec![];
+    for path in &paths {
+        if let Err(err) = collect_files(path, &mut files) {
+            fail(&format!("{}: {}", path.display(), err));
+        }
+    }
+    let reports = files
+        .into_iter()
+        .map(|path| {
+            let config = config::lint_config(&path).unwrap_or_else(|err| fail(&err.to_string()));
+            match fs::read(&path) {
+                Ok(bytes) => FileReport {
+                    diagnostics: lint(&String::from_utf8_lossy(&bytes), &config),
+                    path,
+                },
+                Err(err) => fail(&format!("{}: {}", path.display(), err)),
+            }
+        })
+        .collect::<Vec<_>>();
+    print!("{}", format.render(&reports));
+
+    let errors = reports
+        .iter()
+        .flat_map(|r| &r.diagnostics)
+        .any(|d| d.severity == Some(DiagnosticSeverity::Error));
+    if errors {
+        process::exit(1);
+    }
+}
+
+/// Diagnostics of the source of an Octave file, as the language server reports them.
+fn lint(text: &str, config: &LintConfig) -> Vec<Diagnostic> {
+    let ast = parse(text);
+    get_diagnostics(text, ast.as_ref(), config)
+}
+
+/// Adds the path if it's a file, or the `.m` files under it if it's a directory, skipping hidden
+/// files and directories.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !fs::metadata(path)?.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        let hidden = entry
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if entry.is_dir() {
+            collect_files(&entry, files)?;
+        } else if entry.extension().is_some_and(|e| e == "m") {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("octave-lint: {}", message);
+    process::exit(2)
+}
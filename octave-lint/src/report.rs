@@ -0,0 +1,182 @@
+//! Rendering of the diagnostics of linted files, for people or for other tools.
+use std::path::PathBuf;
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString};
+use serde_json::{json, Value};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// One `path:line:column: severity: message` line per diagnostic
+    Human,
+    /// The LSP diagnostics of each file
+    Json,
+    /// SARIF 2.1.0 log, as read by code scanning services
+    Sarif,
+}
+
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(Self::Human),
+            "json" => Some(Self::Json),
+            "sarif" => Some(Self::Sarif),
+            _ => None,
+        }
+    }
+
+    pub fn render(self, reports: &[FileReport]) -> String {
+        match self {
+            Self::Human => human(reports),
+            Self::Json => pretty(&json(reports)),
+            Self::Sarif => pretty(&sarif(reports)),
+        }
+    }
+}
+
+fn human(reports: &[FileReport]) -> String {
+    let mut out = String::new();
+    for report in reports {
+        for d in &report.diagnostics {
+            let code = match &d.code {
+                Some(NumberOrString::String(code)) => format!(" [{}]", code),
+                Some(NumberOrString::Number(code)) => format!(" [{}]", code),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "{}:{}:{}: {}: {}{}\n",
+                report.path.display(),
+                d.range.start.line + 1,
+                d.range.start.character + 1,
+                severity_name(d.severity),
+                d.message,
+                code
+            ));
+        }
+    }
+    out
+}
+
+fn json(reports: &[FileReport]) -> Value {
+    Value::Array(
+        reports
+            .iter()
+            .map(|r| json!({ "path": r.path, "diagnostics": r.diagnostics }))
+            .collect(),
+    )
+}
+
+/// SARIF log of the diagnostics. Its columns count UTF-16 code units by default, like the ones of
+/// LSP positions.
+fn sarif(reports: &[FileReport]) -> Value {
+    let results = reports
+        .iter()
+        .flat_map(|r| r.diagnostics.iter().map(move |d| (artifact_uri(r), d)))
+        .map(|(uri, d)| {
+            let mut result = json!({
+                "level": match d.severity {
+                    Some(DiagnosticSeverity::Error) | None => "error",
+                    Some(DiagnosticSeverity::Warning) => "warning",
+                    Some(_) => "note",
+                },
+                "message": { "text": d.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": uri },
+                        "region": {
+                            "startLine": d.range.start.line + 1,
+                            "startColumn": d.range.start.character + 1,
+                            "endLine": d.range.end.line + 1,
+                            "endColumn": d.range.end.character + 1,
+                        },
+                    },
+                }],
+            });
+            match &d.code {
+                Some(NumberOrString::String(code)) => result["ruleId"] = json!(code),
+                Some(NumberOrString::Number(code)) => result["ruleId"] = json!(code.to_string()),
+                None => {}
+            }
+            result
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "octave-lint",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+/// Relative URI reference of a linted file.
+fn artifact_uri(report: &FileReport) -> String {
+    let path = report.path.to_string_lossy().replace('\\', "/");
+    path.strip_prefix("./").unwrap_or(&path).to_string()
+}
+
+fn severity_name(severity: Option<DiagnosticSeverity>) -> &'static str {
+    match severity {
+        Some(DiagnosticSeverity::Error) | None => "error",
+        Some(DiagnosticSeverity::Warning) => "warning",
+        Some(DiagnosticSeverity::Information) => "info",
+        Some(DiagnosticSeverity::Hint) => "hint",
+    }
+}
+
+fn pretty(value: &Value) -> String {
+    format!("{:#}\n", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileReport, OutputFormat};
+    use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Position, Range};
+    use serde_json::Value;
+
+    fn reports() -> Vec<FileReport> {
+        vec![FileReport {
+            path: "./src/f.m".into(),
+            diagnostics: vec![Diagnostic::new(
+                Range::new(Position::new(2, 4), Position::new(2, 9)),
+                Some(DiagnosticSeverity::Warning),
+                Some(NumberOrString::String("duplicate-case".into())),
+                Some("Octave".into()),
+                "Duplicate case value".into(),
+                None,
+                None,
+            )],
+        }]
+    }
+
+    #[test]
+    fn human() {
+        assert_eq!(
+            "./src/f.m:3:5: warning: Duplicate case value [duplicate-case]\n",
+            OutputFormat::Human.render(&reports())
+        );
+    }
+
+    #[test]
+    fn sarif() {
+        let log: Value = serde_json::from_str(&OutputFormat::Sarif.render(&reports())).unwrap();
+        let result = &log["runs"][0]["results"][0];
+        assert_eq!("warning", result["level"]);
+        assert_eq!("duplicate-case", result["ruleId"]);
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!("src/f.m", location["artifactLocation"]["uri"]);
+        assert_eq!(3, location["region"]["startLine"]);
+        assert_eq!(10, location["region"]["endColumn"]);
+    }
+}
@@ -1,13 +1,16 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use flurry::HashMap;
 use tower_lsp::{
     jsonrpc::Result as LspResult, lsp_types::*, Client, LanguageServer, LspService, Server,
 };
 
 use model::Model;
-use octave_parser::node::Tree;
+use octave_parser::config::{self, ConfigError};
+use octave_parser::diagnostics::LintConfig;
+use octave_parser::node::{self, range_to_lsp};
 use octave_typesystem::Type;
-use std::ops::Deref;
 
 mod model;
 
@@ -15,6 +18,9 @@ mod model;
 struct Backend {
     client: Client,
     model: Arc<Model>,
+    /// Lint configurations by the directory of the documents they apply to, until a configuration
+    /// file changes
+    lint_configs: HashMap<PathBuf, LintConfig>,
 }
 
 impl Backend {
@@ -22,6 +28,71 @@ impl Backend {
         Self {
             client,
             model: Arc::new(Model::default()),
+            lint_configs: HashMap::new(),
+        }
+    }
+
+    /// Lint configuration of a document, read once for each directory. Invalid configurations are
+    /// logged, and replaced with the default one.
+    async fn lint_config(&self, uri: &Url) -> LintConfig {
+        let dir = uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf));
+        if let Some(config) = dir
+            .as_ref()
+            .and_then(|dir| self.lint_configs.pin().get(dir).cloned())
+        {
+            return config;
+        }
+        let config = match load_config(uri, config::lint_config) {
+            Ok(config) => config,
+            Err(err) => {
+                self.client.log_message(MessageType::Error, err).await;
+                LintConfig::default()
+            }
+        };
+        if let Some(dir) = dir {
+            self.lint_configs.pin().insert(dir, config.clone());
+        }
+        config
+    }
+
+    /// Reads the lint configurations again if one of the files changed, and lints the open
+    /// documents with them.
+    async fn config_changed(&self, mut files: impl Iterator<Item = &Url>) {
+        let is_config = |uri: &Url| {
+            uri.path_segments()
+                .and_then(|mut segments| segments.next_back())
+                == Some(config::LINT_CONFIG)
+        };
+        if !files.any(is_config) {
+            return;
+        }
+        self.lint_configs.pin().clear();
+        for uri in self.model.uris() {
+            let config = self.lint_config(&uri).await;
+            let diags = self.model.get_diagnostics(&uri, &config);
+            self.client.publish_diagnostics(uri, diags, None).await;
+        }
+    }
+
+    /// Formatting edits of a document, in the style configured for it. Failures are logged, and
+    /// leave the document untouched.
+    async fn format(&self, uri: &Url, range: Option<Range>) -> LspResult<Option<Vec<TextEdit>>> {
+        let style = match load_config(uri, config::format_style) {
+            Ok(style) => style,
+            Err(err) => {
+                self.client.log_message(MessageType::Error, err).await;
+                return Ok(None);
+            }
+        };
+        match self.model.format(uri, range, &style) {
+            Ok(edits) => Ok(Some(edits)),
+            Err(err) => {
+                self.client.log_message(MessageType::Warning, err).await;
+                Ok(None)
+            }
         }
     }
 }
@@ -31,11 +102,21 @@ impl LanguageServer for Backend {
     async fn initialize(&self, _: InitializeParams) -> LspResult<InitializeResult> {
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::Full,
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::Incremental),
+                        save: Some(true.into()),
+                        ..Default::default()
+                    },
                 )),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
-                completion_provider: Some(CompletionOptions::default()),
+                document_formatting_provider: Some(true),
+                document_range_formatting_provider: Some(true),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(vec![".".into()]),
+                    ..Default::default()
+                }),
                 ..Default::default()
             },
             server_info: Some(ServerInfo {
@@ -49,6 +130,26 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::Info, "server initialized")
             .await;
+        // Lint configurations are read again when they change
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: format!("**/{}", config::LINT_CONFIG),
+                kind: None,
+            }],
+        };
+        let registration = Registration {
+            id: "lint-config".into(),
+            method: "workspace/didChangeWatchedFiles".into(),
+            register_options: serde_json::to_value(options).ok(),
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            self.client
+                .log_message(
+                    MessageType::Warning,
+                    format!("Cannot watch lint configurations: {}", err),
+                )
+                .await;
+        }
     }
 
     async fn shutdown(&self) -> LspResult<()> {
@@ -59,7 +160,8 @@ impl LanguageServer for Backend {
         let uri = params.text_document.uri;
         let text = params.text_document.text;
         self.model.set_document(uri.clone(), text);
-        let diags = self.model.get_diagnostics(&uri);
+        let config = self.lint_config(&uri).await;
+        let diags = self.model.get_diagnostics(&uri, &config);
         self.client.publish_diagnostics(uri, diags, None).await;
     }
 
@@ -68,15 +170,43 @@ impl LanguageServer for Backend {
             content_changes,
             text_document: VersionedTextDocumentIdentifier { uri, version },
         } = params;
-        if let Err(err) = self.model.apply_edits(&uri, content_changes, version) {
+        if let Err(err) = self.model.apply_edits(&uri, content_changes) {
             self.client.log_message(MessageType::Error, err).await;
         } else {
-            let diags = self.model.get_diagnostics(&uri);
+            let config = self.lint_config(&uri).await;
+            let diags = self.model.get_diagnostics(&uri, &config);
             self.client.publish_diagnostics(uri, diags, version).await;
         }
     }
 
-    async fn completion(&self, _: CompletionParams) -> LspResult<Option<CompletionResponse>> {
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        self.config_changed(std::iter::once(&params.text_document.uri))
+            .await;
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        self.config_changed(params.changes.iter().map(|change| &change.uri))
+            .await;
+    }
+
+    async fn completion(&self, params: CompletionParams) -> LspResult<Option<CompletionResponse>> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position;
+        if let Some(fields) = self.model.get_fields(&text_document.uri, position) {
+            return Ok(Some(CompletionResponse::Array(
+                fields
+                    .into_iter()
+                    .map(|(s, t)| CompletionItem {
+                        label: s,
+                        detail: Some(t.to_string()),
+                        kind: Some(CompletionItemKind::Field),
+                        ..Default::default()
+                    })
+                    .collect(),
+            )));
+        }
         Ok(Some(CompletionResponse::Array(
             self.model
                 .get_variables()
@@ -96,28 +226,55 @@ impl LanguageServer for Backend {
         )))
     }
 
+    async fn formatting(
+        &self,
+        params: DocumentFormattingParams,
+    ) -> LspResult<Option<Vec<TextEdit>>> {
+        self.format(&params.text_document.uri, None).await
+    }
+
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> LspResult<Option<Vec<TextEdit>>> {
+        self.format(&params.text_document.uri, Some(params.range))
+            .await
+    }
+
     async fn hover(&self, params: HoverParams) -> LspResult<Option<Hover>> {
         let guard = self.model.guard();
-        if let Some(data) = self.model.document(
+        let data = match self.model.document(
             &params.text_document_position_params.text_document.uri,
             &guard,
         ) {
-            Ok(data
-                .ast
-                .at_pos(params.text_document_position_params.position.into())
-                .map(|s| Hover {
-                    contents: HoverContents::Scalar(MarkedString::LanguageString(LanguageString {
-                        language: "text".into(),
-                        value: format!("{}", s.type_of(data.bindings.pin())),
-                    })),
-                    range: Some(Range {
-                        start: s.span().start.into(),
-                        end: s.span().end.into(),
-                    }),
-                }))
-        } else {
-            Ok(None)
-        }
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        Ok(data
+            .ast
+            .at_pos(node::Position::from_lsp(
+                data.text(),
+                params.text_document_position_params.position,
+            ))
+            .map(|s| Hover {
+                contents: HoverContents::Scalar(MarkedString::LanguageString(LanguageString {
+                    language: "text".into(),
+                    value: format!("{}", s.type_of(&data.bindings().pin())),
+                })),
+                range: Some(range_to_lsp(data.text(), s.span())),
+            }))
+    }
+}
+
+/// Configuration of a document from the closest configuration file above it. Documents which
+/// aren't files get the default configuration.
+fn load_config<T: Default>(
+    uri: &Url,
+    load: fn(&Path) -> Result<T, ConfigError>,
+) -> Result<T, ConfigError> {
+    match uri.to_file_path() {
+        Ok(path) => load(&path),
+        Err(_) => Ok(T::default()),
     }
 }
 
@@ -125,10 +282,28 @@ fn get_keywords() -> Vec<String> {
     vec![
         "function",
         "endfunction",
+        "if",
+        "elseif",
+        "else",
+        "endif",
         "for",
         "endfor",
         "while",
         "endwhile",
+        "do",
+        "until",
+        "break",
+        "continue",
+        "switch",
+        "case",
+        "otherwise",
+        "endswitch",
+        "try",
+        "catch",
+        "end_try_catch",
+        "unwind_protect",
+        "unwind_protect_cleanup",
+        "end_unwind_protect",
         "end",
     ]
     .into_iter()
@@ -142,7 +317,7 @@ fn get_type_symbol(ty: &Type) -> CompletionItemKind {
         | Type::Matrix {
             size: Some((1, 1)), ..
         } => CompletionItemKind::Variable,
-        Type::Matrix { .. } => CompletionItemKind::Struct,
+        Type::Matrix { .. } | Type::Cell(_) | Type::Struct(_) => CompletionItemKind::Struct,
         Type::Callable(_) => CompletionItemKind::Function,
         Type::Unknown => CompletionItemKind::Unit,
     }
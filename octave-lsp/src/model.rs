@@ -1,74 +1,114 @@
-use std::ops::{Deref, Range};
-
 use flurry::HashMap;
+use std::cell::OnceCell;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use tower_lsp::lsp_types as lsp;
 use tower_lsp::lsp_types::{Diagnostic, TextDocumentContentChangeEvent, TextEdit, Url};
 
 use flurry::epoch::Guard;
-use lsp_textdocument::{TextDocument, TextDocumentMutationError};
-use octave_parser::ast::{Expr, Statement};
-use octave_parser::node::{Node, Position};
-use octave_parser::parser::parse;
+use octave_parser::ast::Statement;
+use octave_parser::cst::SyntaxNode;
+use octave_parser::diagnostics::{get_diagnostics, LintConfig};
+use octave_parser::format::{format, format_range, FormatError};
+use octave_parser::node::{range_to_lsp, Node, Position};
+use octave_parser::parser::{from_cst, from_edit, parse};
+use octave_parser::printer::Style;
 use octave_typesystem::{CallableType, SimpleType, Type};
-use std::borrow::BorrowMut;
 use thiserror::Error;
 
 #[derive(Clone, Debug, Error)]
 pub enum ModelError {
-    #[error("Text document error: {0}")]
-    TextDocumentError(#[from] TextDocumentMutationError),
     #[error("Unknown document: {0}")]
     UnknownDocument(Url),
+    #[error("Formatting error: {0}")]
+    FormatError(#[from] FormatError),
 }
 
 #[derive(Debug)]
 pub struct DocumentData {
-    pub doc: TextDocument,
+    pub cst: SyntaxNode,
     pub ast: Node<Statement>,
-    pub bindings: HashMap<String, Type>,
+    /// Types of the variables of the document, found on first use rather than on every edit
+    bindings: OnceCell<HashMap<String, Type>>,
+}
+
+impl DocumentData {
+    fn new(cst: SyntaxNode, ast: Node<Statement>) -> Self {
+        DocumentData {
+            cst,
+            ast,
+            bindings: OnceCell::new(),
+        }
+    }
+
+    /// Text of the document, which the syntax tree holds.
+    pub fn text(&self) -> &str {
+        self.cst.text()
+    }
+
+    /// Types of the variables of the document, and of the built-in functions.
+    pub fn bindings(&self) -> &HashMap<String, Type> {
+        self.bindings
+            .get_or_init(|| get_bindings(self.ast.as_ref()))
+    }
 }
 
+/// Documents are locked while an edit takes their AST to build the next one from it.
 #[derive(Debug, Default)]
 pub struct Model {
-    documents: HashMap<Url, DocumentData>,
+    documents: HashMap<Url, Mutex<DocumentData>>,
 }
 
 impl Model {
-    #[allow(dead_code)]
-    pub fn document<'g>(&'g self, uri: &Url, guard: &'g Guard) -> Option<&'g DocumentData> {
-        self.documents.get(uri, guard)
+    pub fn document<'g>(
+        &'g self,
+        uri: &Url,
+        guard: &'g Guard,
+    ) -> Option<MutexGuard<'g, DocumentData>> {
+        self.documents.get(uri, guard).map(lock)
     }
 
     pub fn guard(&self) -> Guard {
         self.documents.guard()
     }
 
+    /// URIs of the open documents.
+    pub fn uris(&self) -> Vec<Url> {
+        self.documents.pin().keys().cloned().collect()
+    }
+
     pub fn apply_edits(
         &self,
         uri: &Url,
         changes: Vec<TextDocumentContentChangeEvent>,
-        version: Option<i64>,
     ) -> Result<(), ModelError> {
-        self.documents
-            .pin()
-            .compute_if_present(uri, |_, DocumentData { doc, .. }| {
-                let mut doc = doc.clone();
-                doc.update(changes, version);
-                let ast = parse(doc.deref());
-                let bindings = get_bindings(ast.as_ref());
-                Some(DocumentData { doc, ast, bindings })
-            })
-            .map(|_| ())
-            .ok_or(ModelError::UnknownDocument(uri.clone()))
+        let guard = self.documents.guard();
+        let mut data = self
+            .document(uri, &guard)
+            .ok_or_else(|| ModelError::UnknownDocument(uri.clone()))?;
+        let mut cst = data.cst.clone();
+        for change in changes {
+            cst = match change.range {
+                Some(range) => {
+                    let start = Position::from_lsp(cst.text(), range.start).offset;
+                    let end = Position::from_lsp(cst.text(), range.end).offset;
+                    cst.edit(start.min(end)..start.max(end), &change.text)
+                }
+                None => SyntaxNode::parse(&change.text),
+            };
+        }
+        // The AST of an empty document stands in for the previous one while the new one is built
+        let ast = std::mem::replace(&mut data.ast, parse(""));
+        let ast = from_edit(&data.cst, ast, &cst);
+        *data = DocumentData::new(cst, ast);
+        Ok(())
     }
 
     pub fn set_document(&self, uri: Url, text: String) {
-        let ast = parse(text.as_str());
-        let bindings = get_bindings(ast.as_ref());
+        let cst = SyntaxNode::parse(&text);
+        let ast = from_cst(&cst);
         let guard = self.documents.guard();
-        let doc = TextDocument::new(uri.clone(), "octave", 0, text);
         self.documents
-            .insert(uri, DocumentData { doc, ast, bindings }, &guard);
+            .insert(uri, Mutex::new(DocumentData::new(cst, ast)), &guard);
     }
 
     pub fn get_variables(&self) -> Vec<(String, Type)> {
@@ -76,7 +116,8 @@ impl Model {
             .pin()
             .values()
             .flat_map(|data| {
-                data.bindings
+                lock(data)
+                    .bindings()
                     .pin()
                     .iter()
                     .map(|(k, v)| (k.clone(), v.clone()))
@@ -86,80 +127,74 @@ impl Model {
             .collect()
     }
 
-    pub fn get_diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+    /// Fields of the struct accessed right before the given position, when completing `s.`.
+    pub fn get_fields(&self, uri: &Url, pos: lsp::Position) -> Option<Vec<(String, Type)>> {
         let guard = self.documents.guard();
-        if let Some(data) = self.documents.get(uri, &guard) {
-            Self::get_diagnostics_stmt(data.ast.as_ref())
-        } else {
-            vec![]
+        let data = self.document(uri, &guard)?;
+        let text = data.text();
+        let start = Position::from_lsp(text, lsp::Position::new(pos.line, 0)).offset;
+        let line = &text[start..Position::from_lsp(text, pos).offset];
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        // Ignore the part of the field name already typed
+        let path = line.trim_end_matches(is_ident).strip_suffix('.')?;
+        let start = path
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| !is_ident(c) && c != '.')
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let mut names = path[start..].split('.');
+        let mut ty = data.bindings().pin().get(names.next()?)?.clone();
+        for name in names {
+            ty = match ty {
+                Type::Struct(s) => s.field(name)?.clone(),
+                _ => return None,
+            };
+        }
+        match ty {
+            Type::Struct(s) => Some(s.fields),
+            _ => None,
         }
     }
 
-    fn get_diagnostics_stmt(node: Node<&Statement>) -> Vec<Diagnostic> {
-        match node.deref() {
-            Statement::Error(s) => vec![Diagnostic::new(
-                parser_range_to_lsp_range(node.span()),
-                lsp::DiagnosticSeverity::Error.into(),
-                None,
-                Some("Octave".into()),
-                s.clone(),
-                None,
-                None,
-            )],
-            Statement::Block(v) => v
-                .iter()
-                .flat_map(|n| Self::get_diagnostics_stmt(n.as_ref()))
-                .collect(),
-            Statement::IgnoreOutput(s) => Self::get_diagnostics_stmt(s.as_deref()),
-            Statement::Assignment(_, e) | Statement::AugAssignment(_, _, e) => {
-                Self::get_diagnostics_expr(e.as_ref())
+    /// Edits formatting the whole document, or only the top-level statements on the lines of
+    /// `range`.
+    pub fn format(
+        &self,
+        uri: &Url,
+        range: Option<lsp::Range>,
+        style: &Style,
+    ) -> Result<Vec<TextEdit>, ModelError> {
+        let guard = self.documents.guard();
+        let data = self
+            .document(uri, &guard)
+            .ok_or_else(|| ModelError::UnknownDocument(uri.clone()))?;
+        let text = data.text();
+        let edit = match range {
+            Some(range) => {
+                let start = Position::from_lsp(text, range.start).offset;
+                let end = Position::from_lsp(text, range.end).offset;
+                format_range(text, start.min(end)..start.max(end), style)?
             }
-            Statement::Expr(e) => Self::get_diagnostics_expr(e.as_ref()),
-            Statement::EOI => vec![],
-        }
+            None => Some((0..text.len(), format(text, style)?)),
+        };
+        Ok(edit
+            .filter(|(range, formatted)| text[range.clone()] != *formatted)
+            .map(|(range, formatted)| {
+                let span =
+                    Position::at_offset(text, range.start)..Position::at_offset(text, range.end);
+                TextEdit::new(range_to_lsp(text, span), formatted)
+            })
+            .into_iter()
+            .collect())
     }
 
-    fn get_diagnostics_expr(node: Node<&Expr>) -> Vec<Diagnostic> {
-        match node.deref() {
-            Expr::Range(s, st, e) => Self::get_diagnostics_expr(s.as_deref())
-                .into_iter()
-                .chain(
-                    st.as_ref()
-                        .map(|n| Self::get_diagnostics_expr(n.as_deref()))
-                        .unwrap_or(vec![])
-                        .into_iter(),
-                )
-                .chain(Self::get_diagnostics_expr(e.as_deref()).into_iter())
-                .collect(),
-            Expr::Error(s) => vec![lsp::Diagnostic::new(
-                parser_range_to_lsp_range(node.span()),
-                lsp::DiagnosticSeverity::Error.into(),
-                None,
-                Some("Octave".into()),
-                s.clone(),
-                None,
-                None,
-            )],
-            Expr::Op(_, a, b) => Self::get_diagnostics_expr(a.as_deref())
-                .into_iter()
-                .chain(Self::get_diagnostics_expr(b.as_deref()).into_iter())
-                .collect(),
-            Expr::Matrix(m) => m
-                .as_ref()
-                .map(|n| Self::get_diagnostics_expr(n.as_ref()))
-                .into_iter()
-                .flat_map(|v| v.into_iter())
-                .collect(),
-            Expr::Call(s, e) => Self::get_diagnostics_expr(s.as_deref())
-                .into_iter()
-                .chain(
-                    e.iter()
-                        .flat_map(|v| Self::get_diagnostics_expr(v.as_ref()).into_iter()),
-                )
-                .collect(),
-            Expr::Decr(e) | Expr::Incr(e) => Self::get_diagnostics_expr(e.as_deref()),
-            _ => vec![],
-        }
+    pub fn get_diagnostics(&self, uri: &Url, config: &LintConfig) -> Vec<Diagnostic> {
+        let guard = self.documents.guard();
+        let data = match self.document(uri, &guard) {
+            Some(data) => data,
+            None => return vec![],
+        };
+        get_diagnostics(data.text(), data.ast.as_ref(), config)
     }
 }
 
@@ -201,22 +236,89 @@ fn get_prelude() -> HashMap<String, Type> {
     map
 }
 
+/// Locks a document, even if an edit panicked while holding it, which leaves the document with an
+/// empty AST that the next edit builds whole again.
+fn lock(data: &Mutex<DocumentData>) -> MutexGuard<'_, DocumentData> {
+    data.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 fn get_bindings(ast: Node<&Statement>) -> HashMap<String, Type> {
     let bindings = get_prelude();
     ast.add_bindings(bindings.pin());
     bindings
 }
 
-fn parser_range_to_lsp_range(range: Range<Position>) -> lsp::Range {
-    lsp::Range {
-        start: parser_pos_to_lsp_pos(range.start),
-        end: parser_pos_to_lsp_pos(range.end),
+#[cfg(test)]
+mod tests {
+    use super::Model;
+    use octave_parser::parser::parse;
+    use tower_lsp::lsp_types::{Position, Range, TextDocumentContentChangeEvent, Url};
+
+    #[test]
+    fn edits() {
+        let model = Model::default();
+        let uri = Url::parse("file:///edits.m").unwrap();
+        model.set_document(uri.clone(), "s = '😀';\nx = 1;\n".into());
+        let change =
+            |start: (u64, u64), end: (u64, u64), text: &str| TextDocumentContentChangeEvent {
+                range: Some(Range::new(
+                    Position::new(start.0, start.1),
+                    Position::new(end.0, end.1),
+                )),
+                range_length: None,
+                text: text.into(),
+            };
+        let changes = vec![change((0, 7), (0, 7), "!"), change((1, 4), (1, 5), "'ü'")];
+        model.apply_edits(&uri, changes).unwrap();
+        let guard = model.guard();
+        let data = model.document(&uri, &guard).unwrap();
+        assert_eq!("s = '😀!';\nx = 'ü';\n", data.text());
+        assert_eq!(0, data.ast.as_ref().get_errors().len());
+        assert!(data.bindings().pin().contains_key("x"));
+    }
+
+    #[test]
+    fn edits_reuse_ast() {
+        let model = Model::default();
+        let uri = Url::parse("file:///reuse.m").unwrap();
+        model.set_document(
+            uri.clone(),
+            "y = x(1); % x\nfunction z = f\n  z = 1;\nend\n".into(),
+        );
+        let change = |line, text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(line, 0), Position::new(line, 0))),
+            range_length: None,
+            text: text.into(),
+        };
+        let changes = vec![change(0, "x = [1 2];\n"), change(3, "  % one\n")];
+        model.apply_edits(&uri, changes).unwrap();
+        let guard = model.guard();
+        let data = model.document(&uri, &guard).unwrap();
+        assert_eq!(parse(data.text()), data.ast);
+        assert!(data.bindings().pin().contains_key("y"));
     }
-}
 
-fn parser_pos_to_lsp_pos(pos: Position) -> lsp::Position {
-    lsp::Position {
-        line: pos.line as u64 - 1,
-        character: pos.col as u64 - 1,
+    #[test]
+    fn fields() {
+        let model = Model::default();
+        let uri = Url::parse("file:///fields.m").unwrap();
+        model.set_document(
+            uri.clone(),
+            "s.x = 1;\ns.y = 'a';\nt = 'é'; u = s.\n".into(),
+        );
+        let names = |pos| {
+            model
+                .get_fields(&uri, pos)
+                .map(|fields| fields.into_iter().map(|(name, _)| name).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            Some(vec!["x".into(), "y".into()]),
+            names(Position::new(2, 15))
+        );
+        assert_eq!(None, names(Position::new(2, 13)));
+        assert_eq!(
+            Some(vec!["x".into(), "y".into()]),
+            names(Position::new(1, 2))
+        );
     }
 }
@@ -0,0 +1,108 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use octave_parser::cst::SyntaxNode;
+use octave_parser::diagnostics::{get_diagnostics, LintConfig};
+use octave_parser::parser::{from_cst, from_edit};
+
+/// Function file with the given number of functions, about 10 lines each.
+fn document(functions: usize) -> String {
+    (0..functions)
+        .map(|i| {
+            format!(
+                "% Scales the input by {0}
+function y = f{0}(x)
+  y = zeros(size(x));
+  for k = 1:numel(x)
+    if x(k) > {0}
+      y(k) = x(k) * {0}; % large
+    else
+      y(k) = [x(k), 'a''b'];
+    end
+  end
+end
+",
+                i
+            )
+        })
+        .collect()
+}
+
+/// Script with the given number of assignments, each followed by a block using them, 4 lines
+/// together, all at the top level.
+fn script(assignments: usize) -> String {
+    (0..assignments)
+        .map(|i| {
+            format!(
+                "x{{{0}}} = [1 2 3] * {0}; % c\nif x{{{0}}}\n  disp(x)\nend\n",
+                i
+            )
+        })
+        .collect()
+}
+
+fn bench_edit(c: &mut Criterion) {
+    let text = document(1000);
+    let cst = SyntaxNode::parse(&text);
+    let offset = text.find("x(k) * 500").unwrap() + "x(k) * 50".len();
+
+    c.bench_function("parse 11k lines", |b| {
+        b.iter(|| SyntaxNode::parse(black_box(&text)))
+    });
+    c.bench_function("edit 11k lines, insert", |b| {
+        b.iter(|| cst.edit(black_box(offset..offset), "1"))
+    });
+    c.bench_function("edit 11k lines, new line", |b| {
+        b.iter(|| cst.edit(black_box(offset..offset), ";\n      z = 2"))
+    });
+}
+
+fn bench_ast(c: &mut Criterion) {
+    let text = document(1000);
+    let cst = SyntaxNode::parse(&text);
+    let ast = from_cst(&cst);
+    let offset = text.find("x(k) * 500").unwrap() + "x(k) * 50".len();
+
+    c.bench_function("build AST 11k lines", |b| {
+        b.iter(|| from_cst(black_box(&cst)))
+    });
+    c.bench_function("edit and rebuild AST 11k lines", |b| {
+        b.iter_batched(
+            || ast.clone(),
+            |ast| {
+                let edited = cst.edit(black_box(offset..offset), ";\n      z = 2");
+                from_edit(&cst, ast, &edited)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    // Statements after a new variable are checked for indexing it
+    let text = script(3000);
+    let script_cst = SyntaxNode::parse(&text);
+    let script_ast = from_cst(&script_cst);
+    let middle = text[..text.len() / 2].rfind('\n').unwrap() + 1;
+    c.bench_function("edit and rebuild AST 12k lines, new variable", |b| {
+        b.iter_batched(
+            || script_ast.clone(),
+            |ast| {
+                let edited = script_cst.edit(black_box(middle..middle), "z = 1;\n");
+                from_edit(&script_cst, ast, &edited)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    // What the language server does on every change of a document
+    let config = LintConfig::default();
+    c.bench_function("edit, rebuild AST and lint 11k lines", |b| {
+        b.iter_batched(
+            || ast.clone(),
+            |ast| {
+                let edited = cst.edit(black_box(offset..offset), ";\n      z = 2");
+                let ast = from_edit(&cst, ast, &edited);
+                get_diagnostics(edited.text(), ast.as_ref(), &config)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_edit, bench_ast);
+criterion_main!(benches);
@@ -1,7 +1,8 @@
-use crate::node::{Node, Tree};
+use crate::node::Node;
 use crate::value::Matrix;
-use flurry::HashMapRef;
-use octave_typesystem::{SimpleType, Type};
+use crate::visit::{walk_expr, walk_stmt, Visitor};
+use flurry::{HashMap, HashMapRef};
+use octave_typesystem::{CallableType, CellType, IntegerType, SimpleType, StructType, Type};
 use std::ops::Deref;
 use thiserror::Error;
 
@@ -16,61 +17,270 @@ pub enum TypeError {
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Op {
     Add,
     Sub,
     Mul,
     Div,
+    LeftDiv,
     Pow,
+    ElemMul,
+    ElemDiv,
+    ElemLeftDiv,
+    ElemPow,
+    Eq,
+    Ne(Negation),
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    ShortAnd,
+    ShortOr,
     Access,
+    DynamicAccess,
 }
 
+impl Op {
+    /// Operator as written in the source, with `.()` standing for dynamic field access `s.(name)`.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::LeftDiv => "\\",
+            Self::Pow => "^",
+            Self::ElemMul => ".*",
+            Self::ElemDiv => "./",
+            Self::ElemLeftDiv => ".\\",
+            Self::ElemPow => ".^",
+            Self::Eq => "==",
+            Self::Ne(Negation::Bang) => "!=",
+            Self::Ne(Negation::Tilde) => "~=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::And => "&",
+            Self::Or => "|",
+            Self::ShortAnd => "&&",
+            Self::ShortOr => "||",
+            Self::Access => ".",
+            Self::DynamicAccess => ".()",
+        }
+    }
+
+    /// Binding strength of the operator, from loosest to tightest. Prefix operators bind tighter
+    /// than arithmetic operators but looser than power operators, and ranges bind tighter than
+    /// comparisons but looser than arithmetic operators.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Self::ShortOr => 1,
+            Self::ShortAnd => 2,
+            Self::Or => 3,
+            Self::And => 4,
+            Self::Eq | Self::Ne(_) | Self::Lt | Self::Le | Self::Gt | Self::Ge => 5,
+            Self::Add | Self::Sub => 6,
+            Self::Mul
+            | Self::Div
+            | Self::LeftDiv
+            | Self::ElemMul
+            | Self::ElemDiv
+            | Self::ElemLeftDiv => 7,
+            Self::Pow | Self::ElemPow => 8,
+            Self::Access | Self::DynamicAccess => 9,
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Self::Eq | Self::Ne(_) | Self::Lt | Self::Le | Self::Gt | Self::Ge
+        )
+    }
+
+    pub fn is_logical(&self) -> bool {
+        matches!(self, Self::And | Self::Or | Self::ShortAnd | Self::ShortOr)
+    }
+
+    /// Type of the result of applying the operator on operands of the given types.
+    pub fn type_of(&self, lhs: Type, rhs: Type) -> Type {
+        let size = match (self, lhs.size(), rhs.size()) {
+            // Field types depend on the field name, which only `Expr::type_of` knows about
+            (Self::Access, ..) | (Self::DynamicAccess, ..) => return Type::Unknown,
+            (Self::ShortAnd, ..) | (Self::ShortOr, ..) => Some((1, 1)),
+            (Self::Mul, Some((1, 1)), s) | (Self::Mul, s, Some((1, 1))) => s,
+            (Self::Mul, Some((rows, _)), Some((_, cols))) => Some((rows, cols)),
+            (Self::Div, s, Some((1, 1))) => s,
+            (Self::Div, Some((rows, _)), Some((cols, _))) => Some((rows, cols)),
+            (Self::LeftDiv, Some((1, 1)), s) => s,
+            (Self::LeftDiv, Some((_, rows)), Some((_, cols))) => Some((rows, cols)),
+            (Self::Pow, s, Some((1, 1))) | (Self::Pow, Some((1, 1)), s) => s,
+            (Self::Mul, ..) | (Self::Div, ..) | (Self::LeftDiv, ..) | (Self::Pow, ..) => None,
+            // Element-wise operators
+            (_, Some((1, 1)), s) | (_, s, Some((1, 1))) => s,
+            (_, Some(a), Some(b)) if a == b => Some(a),
+            _ => None,
+        };
+        let ty = if self.is_comparison() || self.is_logical() {
+            SimpleType::Logical
+        } else {
+            lhs.element_type().arithmetic(rhs.element_type())
+        };
+        Type::Matrix { size, ty }
+    }
+}
+
+/// Unary operators, except for the postfix increment and decrement which are `Expr::Incr` and
+/// `Expr::Decr`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum UnaryOp {
+    Neg,
+    Plus,
+    Not(Negation),
+    PreIncr,
+    PreDecr,
+    Transpose,
+    CTranspose,
+}
+
+impl UnaryOp {
+    /// Operator as written in the source.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Neg => "-",
+            Self::Plus => "+",
+            Self::Not(Negation::Bang) => "!",
+            Self::Not(Negation::Tilde) => "~",
+            Self::PreIncr => "++",
+            Self::PreDecr => "--",
+            Self::Transpose => ".'",
+            Self::CTranspose => "'",
+        }
+    }
+
+    pub fn is_postfix(&self) -> bool {
+        matches!(self, Self::Transpose | Self::CTranspose)
+    }
+
+    /// Type of the result of applying the operator on an operand of the given type.
+    pub fn type_of(&self, ty: Type) -> Type {
+        match (self, ty) {
+            (Self::PreIncr, _) | (Self::PreDecr, _) => Type::SimpleType(SimpleType::Void),
+            (_, Type::Unknown) => Type::Unknown,
+            (Self::Transpose, ty) | (Self::CTranspose, ty) => match ty {
+                Type::Matrix { size, ty } => Type::Matrix {
+                    size: size.map(|(rows, cols)| (cols, rows)),
+                    ty,
+                },
+                ty => ty,
+            },
+            (Self::Not(_), ty) => Type::Matrix {
+                size: ty.size(),
+                ty: SimpleType::Logical,
+            },
+            (Self::Neg, ty) | (Self::Plus, ty) => Type::Matrix {
+                size: ty.size(),
+                ty: ty.element_type().arithmetic(ty.element_type()),
+            },
+        }
+    }
+}
+
+/// Spelling of the negation in `!=` and `!`, which MATLAB only accepts as `~=` and `~`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Negation {
+    Bang,
+    Tilde,
+}
+
+/// String literal, keeping its source text along with its value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StringLiteral {
+    /// Value of the string, with escape sequences and doubled quotes resolved
+    pub value: String,
+    /// Source text of the literal, including its quotes
+    pub raw: String,
+}
+
+impl StringLiteral {
+    pub fn is_double_quoted(&self) -> bool {
+        self.raw.starts_with('"')
+    }
+}
+
+/// Decimal number literal, keeping its source text along with its value.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NumberLiteral {
+    pub value: f64,
+    /// Source text of the literal, such as `1e-3` or `2.5j`, starting with `-` when negative
+    pub raw: String,
+}
+
+impl NumberLiteral {
+    /// Literal of the opposite value, as negative literals are folded into a single literal.
+    pub(crate) fn negate(self) -> Self {
+        Self {
+            value: -self.value,
+            raw: match self.raw.strip_prefix('-') {
+                Some(raw) => raw.to_string(),
+                None => format!("-{}", self.raw),
+            },
+        }
+    }
+}
+
+/// Hexadecimal or binary integer literal, keeping its source text along with its value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IntegerLiteral {
+    pub value: u64,
+    pub ty: IntegerType,
+    /// Source text of the literal, such as `0b1010` or `0x1Fu8`
+    pub raw: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Expr {
     Error(String),
-    LitString(String),
-    LitNumber(f64),
+    LitString(StringLiteral),
+    LitNumber(NumberLiteral),
+    /// Imaginary number literal such as `2.5e-3j`, holding its imaginary part
+    LitComplex(NumberLiteral),
+    LitInteger(IntegerLiteral),
     Identifier(String),
     Matrix(Matrix<Node<Expr>>),
+    Cell(Matrix<Node<Expr>>),
     Op(Op, Node<Box<Expr>>, Node<Box<Expr>>),
+    Unary(UnaryOp, Node<Box<Expr>>),
     Incr(Node<Box<Expr>>),
     Decr(Node<Box<Expr>>),
     Range(Node<Box<Expr>>, Option<Node<Box<Expr>>>, Node<Box<Expr>>),
     Call(Node<Box<Expr>>, Vec<Node<Expr>>),
-}
-
-impl Tree for Expr {
-    type Item = Node<Self>;
-
-    fn children(&self) -> Vec<Self::Item> {
-        match self {
-            Self::Matrix(m) => m.data.clone(),
-            Self::Op(_, a, b) => vec![
-                a.as_deref().map(|v| v.clone()),
-                b.as_deref().map(|v| v.clone()),
-            ],
-            Self::Range(s, Some(st), e) => vec![
-                s.as_deref().map(Clone::clone),
-                st.as_deref().map(Clone::clone),
-                e.as_deref().map(Clone::clone),
-            ],
-            Self::Range(s, None, e) => vec![
-                s.as_deref().map(Clone::clone),
-                e.as_deref().map(Clone::clone),
-            ],
-            Self::Call(c, v) => std::iter::once(c.as_deref().map(Clone::clone))
-                .chain(v.iter().map(|n| n.as_ref().map(Clone::clone)))
-                .collect(),
-            Self::Decr(e) | Self::Incr(e) => vec![e.as_deref().map(Clone::clone)],
-            _ => vec![],
-        }
-    }
+    /// Command syntax such as `hold on`, calling a function with its words as strings
+    Command(Node<Box<Expr>>, Vec<Node<Expr>>),
+    Index(Node<Box<Expr>>, Vec<Node<Expr>>),
+    CellIndex(Node<Box<Expr>>, Vec<Node<Expr>>),
+    AnonFunction(Vec<Node<String>>, Node<Box<Expr>>),
+    Handle(Node<String>),
+    /// Whole dimension selector, as in `a(:, 1)`
+    Colon,
+    /// Last index of the dimension being indexed, as in `a(end)`
+    End,
 }
 
 impl Expr {
     pub(crate) fn get_str_matrix(&self) -> Option<Matrix<&str>> {
         match self {
-            Expr::LitString(s) => Some(Matrix::from_vecs(vec![vec![s.as_str()]])),
+            Expr::LitString(s) => Some(Matrix::from_vecs(vec![vec![s.value.as_str()]])),
             Expr::Matrix(m) => m
                 .as_ref()
                 .map(|Node { data, .. }| data.get_str())
@@ -81,51 +291,160 @@ impl Expr {
 
     fn get_str(&self) -> Option<&str> {
         match self {
-            Expr::LitString(s) => Some(s.as_str()),
+            Expr::LitString(s) => Some(s.value.as_str()),
             _ => None,
         }
     }
 
-    pub fn type_of(&self, ctx: HashMapRef<String, Type>) -> Type {
+    pub fn type_of(&self, ctx: &HashMapRef<String, Type>) -> Type {
         match self {
             Self::LitString(_) => Type::SimpleType(SimpleType::String),
             Self::LitNumber(_) => Type::SimpleType(SimpleType::Double),
+            Self::LitComplex(_) => Type::SimpleType(SimpleType::Complex),
+            Self::LitInteger(n) => Type::SimpleType(SimpleType::Integer(n.ty)),
             Self::Range(s, st, e) => Type::Matrix {
                 size: None,
                 ty: s
-                    .type_of(ctx.clone())
+                    .type_of(ctx)
                     .simple_type()
-                    .or_else(|| {
-                        st.as_ref()
-                            .and_then(|n| n.type_of(ctx.clone()).simple_type())
-                    })
+                    .or_else(|| st.as_ref().and_then(|n| n.type_of(ctx).simple_type()))
                     .or_else(|| e.type_of(ctx).simple_type())
                     .unwrap_or(SimpleType::Unknown),
             },
+            Self::Unary(op, e) => op.type_of(e.type_of(ctx)),
             Self::Incr(_) | Self::Decr(_) => Type::SimpleType(SimpleType::Void),
-            Self::Call(c, _) => {
-                if let Type::Callable(c) = c.type_of(ctx) {
-                    (*c.return_type).clone()
-                } else {
-                    Type::Unknown
+            Self::Call(c, args) | Self::Command(c, args) => match c.type_of(ctx) {
+                Type::Callable(c) => (*c.return_type).clone(),
+                // Calling a variable is indexing it
+                ty => index_type(ty, args, ctx),
+            },
+            Self::Index(e, args) => match e.type_of(ctx) {
+                // Indexing a function handle calls it
+                Type::Callable(c) => (*c.return_type).clone(),
+                ty => index_type(ty, args, ctx),
+            },
+            Self::AnonFunction(params, body) => {
+                // The body sees the variables of the enclosing scope, captured at definition
+                let locals = HashMap::new();
+                let locals = locals.pin();
+                for (k, v) in ctx.iter() {
+                    locals.insert(k.clone(), v.clone());
+                }
+                for p in params {
+                    locals.insert(p.data.clone(), Type::Unknown);
                 }
+                Type::Callable(CallableType {
+                    args_types: vec![Type::Unknown; params.len()],
+                    return_type: Box::new(body.type_of(&locals)),
+                })
             }
-            Self::Op(_, a, _) => a.type_of(ctx),
+            Self::Handle(name) => match ctx.get(&name.data) {
+                Some(ty @ Type::Callable(_)) => ty.clone(),
+                // Handles to builtins or functions from other files
+                _ => Type::Callable(CallableType {
+                    args_types: vec![],
+                    return_type: Box::new(Type::Unknown),
+                }),
+            },
+            Self::CellIndex(e, args) => match e.type_of(ctx) {
+                Type::Cell(c) => cell_position(&c, args)
+                    .and_then(|(row, col)| c.get(row, col))
+                    .or_else(|| c.common_type())
+                    .cloned()
+                    .unwrap_or(Type::Unknown),
+                _ => Type::Unknown,
+            },
+            Self::Colon => Type::Unknown,
+            Self::End => Type::Matrix {
+                size: Some((1, 1)),
+                ty: SimpleType::Double,
+            },
+            Self::Op(op @ (Op::Access | Op::DynamicAccess), a, b) => match a.type_of(ctx) {
+                Type::Struct(s) => field_name(*op, b)
+                    .and_then(|f| s.field(&f).cloned())
+                    .unwrap_or(Type::Unknown),
+                _ => Type::Unknown,
+            },
+            Self::Op(op, a, b) => op.type_of(a.type_of(ctx), b.type_of(ctx)),
             Self::Identifier(i) => ctx.get(i).cloned().unwrap_or(Type::Unknown),
             Self::Error(_) => Type::Unknown,
-            Self::Matrix(m) => Type::Matrix {
-                size: Some((m.width(), m.height())),
-                ty: m.data[0]
-                    .type_of(ctx)
-                    .simple_type()
-                    .unwrap_or(SimpleType::Unknown),
-            },
+            Self::Matrix(m) => {
+                let types = m.iter().map(|n| n.type_of(ctx)).collect::<Vec<_>>();
+                // Expanding comma-separated lists like `c{:}` makes the size unknown
+                let cs_list = m.iter().any(|n| n.is_cs_list(ctx));
+                let size = if cs_list {
+                    None
+                } else {
+                    concat_size(&types, m.width())
+                };
+                match types.as_slice() {
+                    [] => Type::Matrix {
+                        size: Some((0, 0)),
+                        ty: SimpleType::Double,
+                    },
+                    // `[x]` is just `x`, which is how single values come out of the parser
+                    [ty @ Type::Matrix { .. }]
+                    | [ty @ Type::Callable(_)]
+                    | [ty @ Type::Cell(_)]
+                    | [ty @ Type::Unknown]
+                        if !cs_list =>
+                    {
+                        ty.clone()
+                    }
+                    // Concatenating cell arrays gives a cell array
+                    _ if types.iter().all(|t| matches!(t, Type::Cell(_))) => Type::Cell(CellType {
+                        size,
+                        elements: None,
+                    }),
+                    _ => Type::Matrix {
+                        size,
+                        ty: types
+                            .iter()
+                            .map(Type::element_type)
+                            .fold(types[0].element_type(), SimpleType::promote),
+                    },
+                }
+            }
+            Self::Cell(m) => {
+                if m.iter().any(|n| n.is_cs_list(ctx)) {
+                    Type::Cell(CellType::unknown())
+                } else {
+                    Type::Cell(CellType {
+                        size: Some((m.height(), m.width())),
+                        elements: Some(m.iter().map(|n| n.type_of(ctx)).collect()),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Whether the expression expands to a comma-separated list of values, as `c{:}` does.
+    pub fn is_cs_list(&self, ctx: &HashMapRef<String, Type>) -> bool {
+        match self {
+            Expr::CellIndex(_, args) => args.iter().any(|a| match a.deref() {
+                Expr::Colon => true,
+                e => e.type_of(ctx).size().is_some_and(|s| s != (1, 1)),
+            }),
+            _ => false,
+        }
+    }
+
+    /// Name of the variable an assignment target refers to, e.g. `a` for `a(1).b`.
+    pub fn root_name(&self) -> Option<&str> {
+        match self {
+            Expr::Identifier(i) => Some(i),
+            Expr::Index(e, _)
+            | Expr::CellIndex(e, _)
+            | Expr::Op(Op::Access, e, _)
+            | Expr::Op(Op::DynamicAccess, e, _) => e.root_name(),
+            _ => None,
         }
     }
 
     pub fn get_value(&self) -> Option<f64> {
         match self {
-            Expr::LitNumber(v) => Some(*v),
+            Expr::LitNumber(n) => Some(n.value),
+            Expr::LitInteger(n) => Some(n.value as f64),
             _ => None,
         }
     }
@@ -138,61 +457,259 @@ impl Expr {
     }
 }
 
+/// Type of the result of indexing a value of type `ty` with the given arguments.
+fn index_type(ty: Type, args: &[Node<Expr>], ctx: &HashMapRef<String, Type>) -> Type {
+    if matches!(ty, Type::Unknown | Type::Callable(_)) {
+        return Type::Unknown;
+    }
+    // `None` stands for `:`
+    let arg_types = args
+        .iter()
+        .map(|a| match a.deref() {
+            Expr::Colon => None,
+            e => Some(e.type_of(ctx)),
+        })
+        .collect::<Vec<_>>();
+    // Number of elements selected by an argument along a dimension of length `len`
+    let count = |arg: &Option<Type>, len: Option<usize>| match arg {
+        None => len,
+        Some(t) if t.element_type() == SimpleType::Logical => None,
+        Some(t) => t.size().map(|(rows, cols)| rows * cols),
+    };
+    let size = ty.size();
+    let size = match arg_types.as_slice() {
+        [] => size,
+        [None] => size.map(|(rows, cols)| (rows * cols, 1)),
+        [Some(t)] => match (size, t.size()) {
+            _ if t.element_type() == SimpleType::Logical => None,
+            (_, Some((1, 1))) => Some((1, 1)),
+            // Indexing a vector with a linear index keeps its orientation
+            (Some((1, _)), Some((rows, cols))) => Some((1, rows * cols)),
+            (Some((_, 1)), Some((rows, cols))) => Some((rows * cols, 1)),
+            (_, s) => s,
+        },
+        [rows, cols] => count(rows, size.map(|s| s.0)).zip(count(cols, size.map(|s| s.1))),
+        _ => None,
+    };
+    match ty {
+        // Indexing a struct array gives structs with the same fields
+        Type::Struct(s) => Type::Struct(s),
+        Type::Cell(c) => Type::Cell(CellType {
+            size,
+            elements: cell_position(&c, args)
+                .and_then(|(row, col)| c.get(row, col))
+                .filter(|_| size == Some((1, 1)))
+                .map(|t| vec![t.clone()]),
+        }),
+        ty => Type::Matrix {
+            size,
+            ty: ty.element_type(),
+        },
+    }
+}
+
+/// Type of the error bound by `catch err`, mirroring the properties of `MException`.
+fn exception_type() -> StructType {
+    let string = || Type::SimpleType(SimpleType::String);
+    let double = || Type::SimpleType(SimpleType::Double);
+    StructType {
+        fields: vec![
+            ("message".into(), string()),
+            ("identifier".into(), string()),
+            (
+                "stack".into(),
+                Type::Struct(StructType {
+                    fields: vec![
+                        ("file".into(), string()),
+                        ("name".into(), string()),
+                        ("line".into(), double()),
+                        ("column".into(), double()),
+                    ],
+                }),
+            ),
+        ],
+    }
+}
+
+/// Type of the variable at the root of an assignment target after assigning a value of type
+/// `value` to the target.
+fn assigned_type(target: &Expr, value: Type, ctx: &HashMapRef<String, Type>) -> Type {
+    match target {
+        Expr::Identifier(_) => value,
+        Expr::Index(base, _) => {
+            // Assigning to an index can grow the matrix
+            let ty = match (base.type_of(ctx), value) {
+                (Type::Matrix { ty, .. }, value) | (Type::SimpleType(ty), value)
+                    if ty != SimpleType::Void =>
+                {
+                    Type::Matrix {
+                        size: None,
+                        ty: ty.promote(value.element_type()),
+                    }
+                }
+                (Type::Cell(_), _) | (Type::Unknown, Type::Cell(_)) => {
+                    Type::Cell(CellType::unknown())
+                }
+                (Type::Struct(s), _) | (Type::Unknown, Type::Struct(s)) => Type::Struct(s),
+                (Type::Unknown, value) => Type::Matrix {
+                    size: None,
+                    ty: value.element_type(),
+                },
+                _ => Type::Unknown,
+            };
+            assigned_type(base, ty, ctx)
+        }
+        Expr::CellIndex(base, args) => {
+            let ty = match base.type_of(ctx) {
+                // Assigning to an existing element keeps the size
+                Type::Cell(mut c) => match cell_position(&c, args) {
+                    Some((row, col)) if c.get(row, col).is_some() => {
+                        let cols = c.size.map_or(0, |s| s.1);
+                        if let Some(elements) = c.elements.as_mut() {
+                            elements[row * cols + col] = value;
+                        }
+                        c
+                    }
+                    _ => CellType::unknown(),
+                },
+                _ => CellType::unknown(),
+            };
+            assigned_type(base, Type::Cell(ty), ctx)
+        }
+        Expr::Op(op @ (Op::Access | Op::DynamicAccess), base, field) => {
+            let mut s = match base.type_of(ctx) {
+                Type::Struct(s) => s,
+                _ => StructType::default(),
+            };
+            if let Some(name) = field_name(*op, field) {
+                s.set_field(name, value);
+            }
+            assigned_type(base, Type::Struct(s), ctx)
+        }
+        _ => Type::Unknown,
+    }
+}
+
+/// Name of the field accessed by `s.name` or `s.('name')`, when known statically.
+fn field_name(op: Op, field: &Expr) -> Option<String> {
+    match (op, field) {
+        (Op::Access, Expr::Identifier(name)) => Some(name.clone()),
+        (Op::DynamicAccess, e) => e
+            .get_str_matrix()
+            .filter(|m| m.len() == 1)
+            .map(|m| m.data[0].to_string()),
+        _ => None,
+    }
+}
+
+/// Zero-based position of the element selected by literal indices in a cell array.
+fn cell_position(c: &CellType, args: &[Node<Expr>]) -> Option<(usize, usize)> {
+    let indices = args
+        .iter()
+        .map(|a| {
+            let value = a
+                .get_value()
+                .or_else(|| a.get_matrix().filter(|m| m.len() == 1).map(|m| m.data[0]))?;
+            if value >= 1.0 && value.fract() == 0.0 {
+                Some(value as usize - 1)
+            } else {
+                None
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match indices.as_slice() {
+        // Linear indices go down columns first
+        [i] => {
+            let (rows, _) = c.size?;
+            Some((i.checked_rem(rows)?, i / rows))
+        }
+        [row, col] => Some((*row, *col)),
+        _ => None,
+    }
+}
+
+/// Size of the concatenation of values of the given types, laid out in rows of `width` elements.
+fn concat_size(types: &[Type], width: usize) -> Option<(usize, usize)> {
+    if width == 0 {
+        return Some((0, 0));
+    }
+    let mut size = None;
+    for row in types.chunks(width) {
+        let sizes = row.iter().map(Type::size).collect::<Option<Vec<_>>>()?;
+        let rows = sizes[0].0;
+        if sizes.iter().any(|(r, _)| *r != rows) {
+            return None;
+        }
+        let cols = sizes.iter().map(|(_, c)| c).sum();
+        size = match size {
+            None => Some((rows, cols)),
+            Some((r, c)) if c == cols => Some((r + rows, c)),
+            Some(_) => return None,
+        };
+    }
+    size
+}
+
 impl<'a> Node<&'a Expr> {
     pub fn get_errors(&self) -> Vec<Node<String>> {
-        match &self.data {
-            Expr::Error(s) => vec![Node {
-                span: self.span.clone(),
-                data: s.clone(),
-            }],
-            Expr::Matrix(m) => m
-                .data
-                .iter()
-                .flat_map(|n| n.as_ref().get_errors().into_iter())
-                .collect(),
-            Expr::Decr(n) => n.as_deref().get_errors(),
-            Expr::Incr(n) => n.as_deref().get_errors(),
-            Expr::Range(start, range, end) => start
-                .as_deref()
-                .get_errors()
-                .into_iter()
-                .chain(
-                    range
-                        .as_ref()
-                        .map(|n| n.as_deref().get_errors())
-                        .unwrap_or(vec![])
-                        .into_iter(),
-                )
-                .chain(end.as_deref().get_errors().into_iter())
-                .collect(),
+        let mut errors = Errors::default();
+        errors.visit_expr(self.clone());
+        errors.0
+    }
+
+    /// Literal values matched by a `case` label, e.g. both values of `case {1, 'a'}`.
+    pub fn case_values(&self) -> Vec<Node<&'a Expr>> {
+        let data: &'a Expr = self.data;
+        match data {
+            Expr::LitNumber(_) | Expr::LitComplex(_) | Expr::LitInteger(_) | Expr::LitString(_) => {
+                vec![self.clone()]
+            }
+            Expr::Matrix(m) if m.len() == 1 => m.data[0].as_ref().case_values(),
+            Expr::Cell(m) => m.iter().flat_map(|n| n.as_ref().case_values()).collect(),
             _ => vec![],
         }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum Statement {
     Error(String),
     IgnoreOutput(Node<Box<Statement>>),
     Expr(Node<Expr>),
-    Assignment(String, Node<Expr>),
+    Assignment(Node<Expr>, Node<Expr>),
     AugAssignment(String, Op, Node<Expr>),
     Block(Vec<Node<Statement>>),
+    If(
+        Vec<(Node<Expr>, Node<Statement>)>,
+        Option<Node<Box<Statement>>>,
+    ),
+    Switch(
+        Node<Expr>,
+        Vec<(Node<Expr>, Node<Statement>)>,
+        Option<Node<Box<Statement>>>,
+    ),
+    Try(
+        Node<Box<Statement>>,
+        Option<Node<String>>,
+        Option<Node<Box<Statement>>>,
+    ),
+    UnwindProtect(Node<Box<Statement>>, Node<Box<Statement>>),
+    Function(
+        Vec<Node<String>>,
+        Node<String>,
+        Vec<Node<String>>,
+        Node<Box<Statement>>,
+    ),
+    For(String, Node<Expr>, Node<Box<Statement>>),
+    While(Node<Expr>, Node<Box<Statement>>),
+    DoUntil(Node<Box<Statement>>, Node<Expr>),
+    Break,
+    Continue,
     EOI,
 }
 
-impl Tree for Statement {
-    type Item = Node<Self>;
-
-    fn children(&self) -> Vec<Self::Item> {
-        match self {
-            Self::Block(v) => v.iter().map(|v| v.clone()).collect(),
-            Self::IgnoreOutput(n) => vec![n.as_deref().map(Clone::clone)],
-            _ => vec![],
-        }
-    }
-}
-
 impl Statement {
     pub fn get_matrix(&self) -> Option<Matrix<f64>> {
         match self {
@@ -213,37 +730,129 @@ impl Statement {
     }
 
     pub fn add_bindings(&self, ctx: HashMapRef<String, Type>) {
-        match self {
-            Self::Assignment(i, e) => {
-                ctx.insert(i.clone(), e.type_of(ctx.clone()));
+        Bindings {
+            ctx,
+            shadowed: None,
+        }
+        .bind(self)
+    }
+}
+
+impl Node<&Statement> {
+    pub fn get_errors(&self) -> Vec<Node<String>> {
+        let mut errors = Errors::default();
+        errors.visit_stmt(self.clone());
+        errors.0
+    }
+}
+
+/// Error nodes of a tree, in source order.
+#[derive(Default)]
+struct Errors(Vec<Node<String>>);
+
+impl<'a> Visitor<'a> for Errors {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        if let Statement::Error(s) = node.data {
+            self.0.push(node.clone().map(|_| s.clone()));
+        }
+        walk_stmt(self, node.data)
+    }
+
+    fn visit_expr(&mut self, node: Node<&'a Expr>) {
+        if let Expr::Error(s) = node.data {
+            self.0.push(node.clone().map(|_| s.clone()));
+        }
+        walk_expr(self, node.data)
+    }
+}
+
+/// Adds the variables and functions bound by the statements it visits to its context.
+struct Bindings<'c> {
+    ctx: HashMapRef<'c, String, Type>,
+    /// Names bound within the function being typed, along with the types they had before, so
+    /// that the context can be restored when leaving the function
+    shadowed: Option<Vec<(String, Option<Type>)>>,
+}
+
+impl Bindings<'_> {
+    fn insert(&mut self, name: String, ty: Type) {
+        let previous = self.ctx.insert(name.clone(), ty).cloned();
+        if let Some(shadowed) = &mut self.shadowed {
+            shadowed.push((name, previous));
+        }
+    }
+
+    fn bind(&mut self, stmt: &Statement) {
+        let ctx = &self.ctx;
+        match stmt {
+            Statement::Assignment(target, e) => {
+                if let Some(i) = target.root_name() {
+                    let ty = assigned_type(target, e.type_of(ctx), ctx);
+                    self.insert(i.to_string(), ty);
+                }
             }
-            Self::Block(v) => {
-                for s in v {
-                    s.add_bindings(ctx.clone());
+            Statement::Try(b, err, c) => {
+                self.bind(b);
+                if let Some(err) = err {
+                    self.insert(err.data.clone(), Type::Struct(exception_type()));
+                }
+                if let Some(c) = c {
+                    self.bind(c);
                 }
             }
-            Self::IgnoreOutput(s) => s.add_bindings(ctx),
-            _ => {}
+            Statement::Function(outputs, name, inputs, body) => {
+                let ty = |return_type| {
+                    Type::Callable(CallableType {
+                        args_types: vec![Type::Unknown; inputs.len()],
+                        return_type: Box::new(return_type),
+                    })
+                };
+                // Registered before typing the body so that recursive calls resolve
+                self.insert(name.data.clone(), ty(Type::Unknown));
+                // The body sees the enclosing context, which gets back its own bindings after
+                let outer = self.shadowed.replace(vec![]);
+                for i in inputs {
+                    self.insert(i.data.clone(), Type::Unknown);
+                }
+                self.bind(body);
+                let ctx = &self.ctx;
+                let return_type = match outputs.first() {
+                    Some(o) => ctx.get(&o.data).cloned().unwrap_or(Type::Unknown),
+                    None => Type::SimpleType(SimpleType::Void),
+                };
+                let shadowed = std::mem::replace(&mut self.shadowed, outer).unwrap_or_default();
+                for (name, ty) in shadowed.into_iter().rev() {
+                    match ty {
+                        Some(ty) => ctx.insert(name, ty),
+                        None => ctx.remove(&name),
+                    };
+                }
+                self.insert(name.data.clone(), ty(return_type));
+            }
+            Statement::For(i, e, b) => {
+                let ty = match e.deref() {
+                    Expr::Range(..) => match e.type_of(ctx) {
+                        Type::Matrix { ty, .. } => Type::Matrix {
+                            size: Some((1, 1)),
+                            ty,
+                        },
+                        ty => ty,
+                    },
+                    _ => e.type_of(ctx).column(),
+                };
+                self.insert(i.clone(), ty);
+                self.bind(b);
+            }
+            _ => walk_stmt(self, stmt),
         }
     }
 }
 
-impl<'a> Node<&'a Statement> {
-    pub fn get_errors(&'a self) -> Vec<Node<String>> {
-        match &self.data {
-            Statement::Error(s) => vec![Node {
-                span: self.span.clone(),
-                data: s.clone(),
-            }],
-            Statement::IgnoreOutput(s) => s.as_deref().get_errors(),
-            Statement::Expr(e) => e.as_ref().get_errors(),
-            Statement::Assignment(_, e) => e.as_ref().get_errors(),
-            Statement::AugAssignment(_, _, e) => e.as_ref().get_errors(),
-            Statement::Block(vs) => vs
-                .iter()
-                .flat_map(|n| n.as_ref().get_errors().into_iter())
-                .collect(),
-            Statement::EOI => vec![],
-        }
+impl<'a> Visitor<'a> for Bindings<'_> {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        self.bind(node.data)
     }
+
+    // Expressions don't bind anything
+    fn visit_expr(&mut self, _node: Node<&'a Expr>) {}
 }
@@ -0,0 +1,76 @@
+//! Project configuration, read from the closest file with a given name in the directories
+//! containing a document.
+use crate::diagnostics::LintConfig;
+use crate::printer::Style;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// File holding the formatting `Style` of the documents under its directory.
+pub const FORMAT_CONFIG: &str = ".octave-format.toml";
+/// File holding the `LintConfig` of the documents under its directory.
+pub const LINT_CONFIG: &str = ".octave-lint.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Cannot read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid configuration in {0}: {1}")]
+    Toml(PathBuf, toml::de::Error),
+}
+
+pub fn format_style(document: &Path) -> Result<Style, ConfigError> {
+    load(document, FORMAT_CONFIG)
+}
+
+pub fn lint_config(document: &Path) -> Result<LintConfig, ConfigError> {
+    load(document, LINT_CONFIG)
+}
+
+/// Configuration from the closest file with the given name, or the default one if there's none.
+fn load<T: DeserializeOwned + Default>(document: &Path, name: &str) -> Result<T, ConfigError> {
+    match find(document, name) {
+        Some(path) => {
+            let text = fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+            toml::from_str(&text).map_err(|e| ConfigError::Toml(path, e))
+        }
+        None => Ok(T::default()),
+    }
+}
+
+/// Closest file with the given name in the directories containing `document`.
+fn find(document: &Path, name: &str) -> Option<PathBuf> {
+    // Relative paths wouldn't lead above the current directory
+    let document = document
+        .canonicalize()
+        .unwrap_or_else(|_| document.to_path_buf());
+    document
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(name))
+        .find(|file| file.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::{Level, LintConfig, Rule};
+    use crate::printer::{EndKeyword, Style};
+
+    #[test]
+    fn parse() {
+        let style: Style =
+            toml::from_str("indent_width = 4\nend_keyword = \"specific\"\n").unwrap();
+        assert_eq!(4, style.indent_width);
+        assert_eq!(EndKeyword::Specific, style.end_keyword);
+        assert!(style.operator_spacing);
+        let config: LintConfig =
+            toml::from_str("[rules]\nunused-variable = \"off\"\nundefined-variable = \"error\"\n")
+                .unwrap();
+        assert_eq!(Level::Off, config.level(Rule::UnusedVariable));
+        assert_eq!(Level::Error, config.level(Rule::UndefinedVariable));
+        assert_eq!(Level::Warning, config.level(Rule::ShadowedBuiltin));
+        assert!(toml::from_str::<LintConfig>("[rules]\nunused-variables = \"off\"\n").is_err());
+        assert!(toml::from_str::<Style>("indent = 4\n").is_err());
+    }
+}
@@ -0,0 +1,1022 @@
+//! Lossless concrete syntax tree, keeping every byte of the source including whitespace and
+//! comments. The AST is derived from it by `parser::from_cst`.
+use crate::node::{Comment, CommentKind, Position};
+use crate::parser::{OctaveParser, Rule};
+use pest::{iterators::Pair, Parser};
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TokenKind {
+    /// Whole text of a node without children, such as an identifier or a number
+    Text,
+    /// Keyword-less syntax not captured by a node, such as `=`, brackets or separators
+    Punct,
+    Whitespace,
+    Newline,
+    /// `...` along with the text ignored after it, or a trailing backslash. The newline ending the
+    /// line is a separate token.
+    Continuation,
+    Comment(CommentKind),
+}
+
+impl TokenKind {
+    /// Whether the token has no meaning for the program, and can be dropped without changing it.
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            Self::Whitespace | Self::Continuation | Self::Comment(_)
+        )
+    }
+}
+
+/// Syntax tree without positions, so that unchanged subtrees can be shared between the trees of
+/// successive versions of a document.
+#[derive(Debug, Eq, PartialEq)]
+struct GreenNode {
+    kind: Rule,
+    len: usize,
+    /// Whether the subtree contains statements that failed to parse
+    errors: bool,
+    elements: Vec<GreenElement>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum GreenElement {
+    Node(Arc<GreenNode>),
+    Token(TokenKind, usize),
+}
+
+impl GreenElement {
+    fn len(&self) -> usize {
+        match self {
+            Self::Node(n) => n.len,
+            Self::Token(_, len) => *len,
+        }
+    }
+}
+
+impl GreenNode {
+    /// Parses a whole text, which is always successful as errors are part of the tree.
+    fn parse(text: &str) -> Arc<Self> {
+        match OctaveParser::parse(Rule::toplevel, text) {
+            Ok(mut pairs) => Self::from_pair(pairs.next().unwrap(), text),
+            // The grammar recovers from errors at the statement level so this shouldn't happen,
+            // but the whole input can still be turned into an error statement
+            Err(_) => {
+                let error = Self {
+                    kind: Rule::error_stmt,
+                    len: text.len(),
+                    errors: true,
+                    elements: vec![GreenElement::Token(TokenKind::Text, text.len())],
+                };
+                Arc::new(Self {
+                    kind: Rule::toplevel,
+                    len: text.len(),
+                    errors: true,
+                    elements: vec![GreenElement::Node(Arc::new(error))],
+                })
+            }
+        }
+    }
+
+    fn from_pair(pair: Pair<Rule>, text: &str) -> Arc<Self> {
+        let range = pair.as_span().start()..pair.as_span().end();
+        let mut elements = vec![];
+        let mut cursor = range.start;
+        let mut children = pair.clone().into_inner().peekable();
+        if children.peek().is_none() && !is_list(pair.as_rule()) {
+            if !range.is_empty() {
+                elements.push(GreenElement::Token(TokenKind::Text, range.len()));
+            }
+        } else {
+            for child in children {
+                let span = child.as_span();
+                lex_gap(&text[cursor..span.start()], &mut elements);
+                elements.push(GreenElement::Node(Self::from_pair(child, text)));
+                cursor = span.end();
+            }
+            lex_gap(&text[cursor..range.end], &mut elements);
+        }
+        let kind = pair.as_rule();
+        let errors = matches!(kind, Rule::error_stmt | Rule::error_keyword)
+            || elements
+                .iter()
+                .any(|e| matches!(e, GreenElement::Node(n) if n.errors));
+        Arc::new(Self {
+            kind,
+            len: range.len(),
+            errors,
+            elements,
+        })
+    }
+
+    /// Tree of this node, at `offset` in the old text, after an edit within one of its blocks,
+    /// parsing again only the statements around the edit in the innermost block containing it.
+    /// Returns `None` when the edit isn't strictly within a block of a statement without errors,
+    /// or when it can change how the block itself is parsed.
+    fn reparse_within(
+        &self,
+        offset: usize,
+        old: &str,
+        new: &str,
+        range: &Range<usize>,
+        inserted: usize,
+    ) -> Option<Arc<Self>> {
+        let mut start = offset;
+        for (i, e) in self.elements.iter().enumerate() {
+            let end = start + e.len();
+            match e {
+                GreenElement::Node(child) if start < range.start && range.end < end => {
+                    // Errors can depend on text arbitrarily far from them, including the edit
+                    let errors_before = self.elements[..i]
+                        .iter()
+                        .any(|e| matches!(e, GreenElement::Node(n) if n.errors));
+                    if child.errors || self.kind == Rule::toplevel && errors_before {
+                        return None;
+                    }
+                    let child = child
+                        .reparse_within(start, old, new, range, inserted)
+                        .or_else(|| child.reparse_block(start, old, new, range, inserted))?;
+                    let mut elements = self.elements.clone();
+                    elements[i] = GreenElement::Node(child);
+                    return Some(Arc::new(Self {
+                        kind: self.kind,
+                        len: self.len + inserted - range.len(),
+                        errors: self.errors,
+                        elements,
+                    }));
+                }
+                _ if end > range.start => return None,
+                _ => start = end,
+            }
+        }
+        None
+    }
+
+    /// Tree of this block, at `offset` in the old text, after an edit strictly within it, parsing
+    /// again the statements around the edit like `SyntaxNode::reparse` does for the top level.
+    /// Returns `None` if this isn't a block, or if the edit can change where it ends.
+    fn reparse_block(
+        &self,
+        offset: usize,
+        old: &str,
+        new: &str,
+        range: &Range<usize>,
+        inserted: usize,
+    ) -> Option<Arc<Self>> {
+        if !matches!(self.kind, Rule::block | Rule::function_body) {
+            return None;
+        }
+        let elements = &self.elements;
+        let mut end = offset;
+        let ranges = elements
+            .iter()
+            .map(|e| {
+                end += e.len();
+                end - e.len()..end
+            })
+            .collect::<Vec<_>>();
+        let nodes = elements
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| match e {
+                GreenElement::Node(n) => Some((i, n)),
+                GreenElement::Token(..) => None,
+            })
+            .collect::<Vec<_>>();
+
+        let touched = nodes
+            .iter()
+            .position(|(i, _)| ranges[*i].end >= range.start)
+            .unwrap_or(nodes.len());
+        let kept = match touched {
+            0 => 0,
+            _ => {
+                let (i, node) = nodes[touched - 1];
+                match lookahead_end(node, ranges[i].end, &elements[i + 1..]) {
+                    Some(end) if end < range.start => i + 1,
+                    _ => i,
+                }
+            }
+        };
+        let last = nodes.iter().find(|(i, _)| ranges[*i].start > range.end);
+        let start = ranges[kept].start;
+        let end = last.map_or(end, |(i, _)| ranges[*i].end);
+        let new_end = end + inserted - range.len();
+        let delimiters = ["%{", "#{", "%}", "#}"];
+        if delimiters
+            .iter()
+            .any(|d| old[start..end].contains(d) || new[start..new_end].contains(d))
+        {
+            return None;
+        }
+
+        // Text following the block, unlike the end of the input, can be taken into its last
+        // statement, so the parsed text needs to end with a separator
+        let mut window = Self::parse(&new[start..new_end - last.map_or(0, |(_, n)| n.len)]);
+        let mut reparsed = without_eoi(&window)?;
+        let mut rest = last.map_or(&elements[elements.len()..], |(i, _)| &elements[*i..]);
+        if !is_closed(reparsed, &new[start..]) {
+            let (i, _) = last?;
+            window = Self::parse(&new[start..new_end]);
+            reparsed = without_eoi(&window)?;
+            match reparsed.split_last() {
+                Some((last, init)) if *last == elements[*i] => reparsed = init,
+                _ => return None,
+            }
+            rest = &elements[*i..];
+        }
+        // Statements that are only valid at the top level would end the block
+        let nested = |e: &GreenElement| match e {
+            GreenElement::Node(n) => match n.kind {
+                Rule::function_unterminated => false,
+                Rule::function_def => self.kind != Rule::function_body,
+                _ => true,
+            },
+            GreenElement::Token(..) => true,
+        };
+        if window.errors || !reparsed.iter().all(nested) {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            kind: self.kind,
+            len: self.len + inserted - range.len(),
+            errors: false,
+            elements: elements[..kept]
+                .iter()
+                .chain(reparsed)
+                .chain(rest)
+                .cloned()
+                .collect(),
+        }))
+    }
+
+    /// Calls `f` on every token of the tree, given the offset of the tree in the source.
+    fn walk_tokens(&self, offset: usize, f: &mut impl FnMut(TokenKind, Range<usize>)) {
+        let mut start = offset;
+        for e in &self.elements {
+            match e {
+                GreenElement::Node(n) => n.walk_tokens(start, f),
+                GreenElement::Token(kind, len) => f(*kind, start..start + len),
+            }
+            start += e.len();
+        }
+    }
+}
+
+/// Text of a document, shared by all the nodes and tokens of its tree.
+#[derive(Debug, Eq, PartialEq)]
+struct Source {
+    text: String,
+    lines: LineIndex,
+}
+
+impl Source {
+    fn new(text: String) -> Arc<Self> {
+        let lines = LineIndex::new(&text);
+        Arc::new(Self { text, lines })
+    }
+
+    fn span(&self, range: Range<usize>) -> Range<Position> {
+        self.lines.span(&self.text, range)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct SyntaxToken {
+    kind: TokenKind,
+    range: Range<usize>,
+    source: Arc<Source>,
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> TokenKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.source.text[self.range.clone()]
+    }
+
+    /// Byte range of the token in the source.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    pub fn span(&self) -> Range<Position> {
+        self.source.span(self.range.clone())
+    }
+}
+
+impl fmt::Debug for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SyntaxToken")
+            .field("kind", &self.kind)
+            .field("range", &self.range)
+            .field("text", &self.text())
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+/// Node of the tree of a document. Nodes are cheap to clone, as they share their subtree and the
+/// source with the rest of the tree.
+#[derive(Clone, Eq, PartialEq)]
+pub struct SyntaxNode {
+    green: Arc<GreenNode>,
+    offset: usize,
+    source: Arc<Source>,
+}
+
+impl SyntaxNode {
+    /// Parses a whole document. Printing the resulting tree gives back the input unchanged.
+    pub fn parse(input: &str) -> Self {
+        Self {
+            green: GreenNode::parse(input),
+            offset: 0,
+            source: Source::new(input.into()),
+        }
+    }
+
+    /// Parses a document again after replacing the given byte range of its text, which must be
+    /// the text of this tree. Only the statements around the edit in the innermost block
+    /// containing it are parsed again, and the rest is shared with this tree, unless the edit can
+    /// change how the rest of the document is parsed (for instance when it leaves a block
+    /// unterminated).
+    pub fn edit(&self, range: Range<usize>, text: &str) -> Self {
+        assert_eq!(
+            Rule::toplevel,
+            self.kind(),
+            "Only whole documents can be edited"
+        );
+        let old = &self.source.text;
+        let mut new = String::with_capacity(old.len() - range.len() + text.len());
+        new.push_str(&old[..range.start]);
+        new.push_str(text);
+        new.push_str(&old[range.end..]);
+        let green = self
+            .green
+            .reparse_within(0, old, &new, &range, text.len())
+            .or_else(|| self.reparse(&new, range, text.len()))
+            .unwrap_or_else(|| GreenNode::parse(&new));
+        Self {
+            green,
+            offset: 0,
+            source: Source::new(new),
+        }
+    }
+
+    /// Root of the tree of the edited text, parsing only the statements around the edit, or
+    /// `None` when the whole text needs to be parsed again.
+    fn reparse(&self, new: &str, range: Range<usize>, inserted: usize) -> Option<Arc<GreenNode>> {
+        let old = &self.source.text;
+        let elements = without_eoi(&self.green)?;
+        let mut offset = 0;
+        let ranges = elements
+            .iter()
+            .map(|e| {
+                offset += e.len();
+                offset - e.len()..offset
+            })
+            .collect::<Vec<_>>();
+        let nodes = elements
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| match e {
+                GreenElement::Node(n) => Some((i, n)),
+                GreenElement::Token(..) => None,
+            })
+            .collect::<Vec<_>>();
+
+        // Statements depend on the text following them up to their separator, so the statement
+        // before the edit is parsed again if the edit is within that text. Statements that failed
+        // to parse may have done so because of text arbitrarily far after them, so they are
+        // parsed again too.
+        let touched = nodes
+            .iter()
+            .position(|(i, _)| ranges[*i].end >= range.start)
+            .unwrap_or(nodes.len());
+        let kept = match nodes[..touched].iter().position(|(_, n)| n.errors) {
+            Some(n) => nodes[n].0,
+            None if touched == 0 => 0,
+            None => {
+                let (i, node) = nodes[touched - 1];
+                match lookahead_end(node, ranges[i].end, &elements[i + 1..]) {
+                    Some(end) if end < range.start => i + 1,
+                    _ => i,
+                }
+            }
+        };
+        let last = nodes.iter().find(|(i, _)| ranges[*i].start > range.end);
+        let start = ranges.get(kept).map_or(old.len(), |r| r.start);
+
+        // Block comments can span any number of statements
+        let delimiters = ["%{", "#{", "%}", "#}"];
+        let end = last.map_or(old.len(), |(i, _)| ranges[*i].end);
+        let new_end = end + inserted - range.len();
+        if delimiters
+            .iter()
+            .any(|d| old[start..end].contains(d) || new[start..new_end].contains(d))
+        {
+            return None;
+        }
+
+        // Parse up to the statement following the edit, unless the last statement parsed could
+        // depend on it, in which case it is parsed as well to check that it is unchanged
+        let mut window = GreenNode::parse(&new[start..new_end - last.map_or(0, |(_, n)| n.len)]);
+        let mut rest = last.map_or(&self.green.elements[elements.len()..], |(i, _)| {
+            &self.green.elements[*i..]
+        });
+        let mut reparsed = without_eoi(&window)?;
+        if let Some((i, _)) = last.filter(|_| !is_closed(reparsed, &new[start..])) {
+            window = GreenNode::parse(&new[start..new_end]);
+            reparsed = without_eoi(&window)?;
+            match reparsed.split_last() {
+                Some((last, init)) if *last == elements[*i] => reparsed = init,
+                // The statement following the edit changed as well
+                _ => return self.reparse_from(new, kept),
+            }
+            rest = &self.green.elements[*i..];
+        }
+
+        let unmatched = window.errors || nodes.iter().any(|(i, n)| *i >= kept && n.errors);
+        if let (true, Some(i)) = (unmatched, unterminated(&elements[..kept])) {
+            return self.reparse_from(new, i);
+        }
+        if window.errors {
+            // The statements before the edit are unchanged, so only what follows them is parsed
+            return self.reparse_from(new, kept);
+        }
+
+        Some(Arc::new(GreenNode {
+            kind: Rule::toplevel,
+            len: new.len(),
+            errors: rest
+                .iter()
+                .any(|e| matches!(e, GreenElement::Node(n) if n.errors)),
+            elements: elements[..kept]
+                .iter()
+                .chain(reparsed)
+                .chain(rest)
+                .cloned()
+                .collect(),
+        }))
+    }
+
+    /// Root of the tree of the edited text, sharing the given number of top-level elements and
+    /// parsing all the text after them.
+    fn reparse_from(&self, new: &str, kept: usize) -> Option<Arc<GreenNode>> {
+        if kept == 0 {
+            return None;
+        }
+        let elements = &self.green.elements[..kept];
+        let start = elements.iter().map(GreenElement::len).sum::<usize>();
+        let rest = GreenNode::parse(&new[start..]);
+        if let (true, Some(i)) = (rest.errors, unterminated(elements)) {
+            return self.reparse_from(new, i);
+        }
+        Some(Arc::new(GreenNode {
+            kind: Rule::toplevel,
+            len: new.len(),
+            errors: rest.errors,
+            elements: elements.iter().chain(&rest.elements).cloned().collect(),
+        }))
+    }
+
+    /// Parses a byte range of the source of this tree as the given rule instead, giving a tree
+    /// positioned in the same source. Returns `None` unless the rule matches the whole range.
+    pub(crate) fn parse_range(&self, rule: Rule, range: Range<usize>) -> Option<Self> {
+        let text = &self.source.text[range.clone()];
+        let pair = OctaveParser::parse(rule, text).ok()?.next()?;
+        if pair.as_span().start() != 0 || pair.as_span().end() != text.len() {
+            return None;
+        }
+        Some(Self {
+            green: GreenNode::from_pair(pair, text),
+            offset: range.start,
+            source: self.source.clone(),
+        })
+    }
+
+    pub fn kind(&self) -> Rule {
+        self.green.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.source.text[self.range()]
+    }
+
+    /// Byte range of the node in the source.
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.green.len
+    }
+
+    pub fn span(&self) -> Range<Position> {
+        self.source.span(self.range())
+    }
+
+    /// Whether both nodes share their tree, as the nodes that an edit leaves unchanged do in the
+    /// trees before and after it.
+    pub(crate) fn shares_tree(&self, other: &SyntaxNode) -> bool {
+        Arc::ptr_eq(&self.green, &other.green)
+    }
+
+    /// Child nodes and tokens, in source order.
+    pub fn elements(&self) -> impl Iterator<Item = SyntaxElement> + '_ {
+        let mut offset = self.offset;
+        self.green.elements.iter().map(move |e| {
+            let start = offset;
+            offset += e.len();
+            match e {
+                GreenElement::Node(green) => SyntaxElement::Node(SyntaxNode {
+                    green: green.clone(),
+                    offset: start,
+                    source: self.source.clone(),
+                }),
+                GreenElement::Token(kind, len) => SyntaxElement::Token(SyntaxToken {
+                    kind: *kind,
+                    range: start..start + len,
+                    source: self.source.clone(),
+                }),
+            }
+        })
+    }
+
+    /// Child nodes, in source order.
+    pub fn children(&self) -> impl Iterator<Item = SyntaxNode> + '_ {
+        self.elements().filter_map(|e| match e {
+            SyntaxElement::Node(n) => Some(n),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// All tokens of the tree, in source order.
+    pub fn tokens(&self) -> Vec<SyntaxToken> {
+        let mut tokens = vec![];
+        self.green.walk_tokens(self.offset, &mut |kind, range| {
+            tokens.push(SyntaxToken {
+                kind,
+                range,
+                source: self.source.clone(),
+            })
+        });
+        tokens
+    }
+
+    /// Comments of the tree, in source order.
+    pub fn comments(&self) -> Vec<Comment> {
+        let mut comments = vec![];
+        self.green.walk_tokens(self.offset, &mut |kind, range| {
+            if let TokenKind::Comment(kind) = kind {
+                comments.push(Comment {
+                    span: self.source.span(range.clone()),
+                    kind,
+                    text: self.source.text[range].into(),
+                })
+            }
+        });
+        comments
+    }
+}
+
+impl fmt::Debug for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SyntaxNode")
+            .field("kind", &self.kind())
+            .field("range", &self.range())
+            .field("elements", &self.elements().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = Ok(());
+        self.green.walk_tokens(self.offset, &mut |_, range| {
+            result = result.and_then(|_| f.write_str(&self.source.text[range]))
+        });
+        result
+    }
+}
+
+/// End of the text a top-level statement depends on after its own, given the elements following
+/// it: up to its separator, or up to the keyword of the next function for functions without
+/// terminator. Returns `None` if this text isn't within the elements.
+fn lookahead_end(node: &GreenNode, end: usize, next: &[GreenElement]) -> Option<usize> {
+    if node.kind == Rule::statement_semi {
+        return Some(end);
+    }
+    let mut offset = end;
+    // Newlines after a continuation are skipped as whitespace
+    let mut joined = false;
+    for e in next {
+        match e {
+            GreenElement::Token(kind, len) if kind.is_trivia() => {
+                joined |= *kind == TokenKind::Continuation;
+                offset += len;
+            }
+            GreenElement::Token(TokenKind::Newline, len) if joined => {
+                joined = false;
+                offset += len;
+            }
+            GreenElement::Token(_, len) if node.kind != Rule::function_unterminated => {
+                return Some(offset + len)
+            }
+            GreenElement::Node(_) if node.kind == Rule::function_unterminated => {
+                return Some(offset + "function".len() + 1)
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Whether the last statement of a parsed text is followed by its separator, so that it doesn't
+/// depend on the text following it, and the text doesn't end with a comment or a continuation,
+/// which run until the end of the line.
+fn is_closed(elements: &[GreenElement], text: &str) -> bool {
+    if let Some(GreenElement::Token(TokenKind::Comment(_) | TokenKind::Continuation, _)) =
+        elements.last()
+    {
+        return false;
+    }
+    let last = elements
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, e)| match e {
+            GreenElement::Node(n) => Some((i, n)),
+            GreenElement::Token(..) => None,
+        });
+    let (i, node) = match last {
+        Some(last) => last,
+        None => return true,
+    };
+    let end = elements[..=i].iter().map(GreenElement::len).sum::<usize>();
+    let len = elements.iter().map(GreenElement::len).sum::<usize>();
+    match lookahead_end(node, end, &elements[i + 1..]) {
+        Some(end) => end <= len,
+        // The next function starts right after the text, and the last statement of the body is
+        // terminated, as commands would take the keyword as an argument. Bodies don't contain
+        // functions without terminator, so their text isn't needed.
+        None => {
+            node.kind == Rule::function_unterminated
+                && end == len
+                && text[end..].starts_with("function")
+                && !text[end + "function".len()..]
+                    .starts_with(|c: char| c.is_alphanumeric() || c == '_')
+                && matches!(
+                    node.elements.last(),
+                    Some(GreenElement::Node(body)) if is_closed(&body.elements, "")
+                )
+        }
+    }
+}
+
+/// Index of the first function without terminator among top-level elements. Such functions run
+/// until the next function, but take the first `end` left unmatched after them as their
+/// terminator, so they need to be parsed again when the text after them contains errors.
+fn unterminated(elements: &[GreenElement]) -> Option<usize> {
+    elements
+        .iter()
+        .position(|e| matches!(e, GreenElement::Node(n) if n.kind == Rule::function_unterminated))
+}
+
+/// Top-level elements of a parsed text, if it was parsed to the end.
+fn without_eoi(root: &GreenNode) -> Option<&[GreenElement]> {
+    match root.elements.split_last()? {
+        (GreenElement::Node(eoi), elements) if eoi.kind == Rule::EOI => Some(elements),
+        _ => None,
+    }
+}
+
+/// Whether the rule is made of the nodes it contains and the tokens between them, even when it
+/// contains no node, as an empty block holding only comments does.
+fn is_list(rule: Rule) -> bool {
+    matches!(
+        rule,
+        Rule::block
+            | Rule::function_body
+            | Rule::matrix
+            | Rule::cell
+            | Rule::index_args
+            | Rule::cell_args
+            | Rule::function_params
+            | Rule::function_outputs
+    )
+}
+
+/// Splits text skipped by the grammar between nodes into tokens.
+fn lex_gap(mut text: &str, elements: &mut Vec<GreenElement>) {
+    while !text.is_empty() {
+        let (kind, len) = next_token(text);
+        elements.push(GreenElement::Token(kind, len));
+        text = &text[len..];
+    }
+}
+
+/// Kind and length in bytes of the token at the start of the text, following the `WHITESPACE` and
+/// `COMMENT` rules of the grammar.
+fn next_token(text: &str) -> (TokenKind, usize) {
+    let line_len = |text: &str| text.find(['\r', '\n']).unwrap_or(text.len());
+    if text.starts_with("\r\n") {
+        (TokenKind::Newline, 2)
+    } else if text.starts_with('\n') {
+        (TokenKind::Newline, 1)
+    } else if text.starts_with([' ', '\t', '\r']) {
+        let len = text
+            .find(|c| !matches!(c, ' ' | '\t' | '\r'))
+            .unwrap_or(text.len());
+        // Keep the carriage return of a line ending with the newline
+        if text[..len].ends_with('\r') && text[len..].starts_with('\n') {
+            (TokenKind::Whitespace, len - 1)
+        } else {
+            (TokenKind::Whitespace, len)
+        }
+    } else if let Some(len) = block_comment_len(text) {
+        (TokenKind::Comment(CommentKind::Block), len)
+    } else if text.starts_with(['%', '#']) {
+        (TokenKind::Comment(CommentKind::Line), line_len(text))
+    } else if let Some(rest) = text.strip_prefix("...") {
+        let len = rest.find(['%', '#', '\r', '\n']).unwrap_or(rest.len());
+        (TokenKind::Continuation, len + 3)
+    } else if let Some(rest) = text.strip_prefix('\\') {
+        let len = rest
+            .find(|c| !matches!(c, ' ' | '\t'))
+            .unwrap_or(rest.len());
+        (TokenKind::Continuation, len + 1)
+    } else {
+        let len = text
+            .find([' ', '\t', '\r', '\n', '%', '#', '\\'])
+            .unwrap_or(text.len());
+        let len = text[..len].find("...").filter(|&i| i > 0).unwrap_or(len);
+        (
+            TokenKind::Punct,
+            len.max(text.chars().next().unwrap().len_utf8()),
+        )
+    }
+}
+
+/// Length of the block comment at the start of the text, if there is one. Block comments can be
+/// nested, and their opening delimiter needs to be alone on its line.
+fn block_comment_len(text: &str) -> Option<usize> {
+    if !text.starts_with("%{") && !text.starts_with("#{") {
+        return None;
+    }
+    let after = &text[2..];
+    let blank = after
+        .find(|c| !matches!(c, ' ' | '\t'))
+        .unwrap_or(after.len());
+    let after_blank = &after[blank..];
+    let newline = if after_blank.starts_with("\r\n") {
+        2
+    } else if after_blank.starts_with(['\n', '\r']) {
+        1
+    } else {
+        return None;
+    };
+    let mut i = 2 + blank + newline;
+    loop {
+        let rest = &text[i..];
+        if rest.starts_with("%}") || rest.starts_with("#}") {
+            return Some(i + 2);
+        } else if let Some(len) = block_comment_len(rest) {
+            i += len;
+        } else {
+            i += rest.chars().next()?.len_utf8();
+        }
+    }
+}
+
+/// Byte offsets of the start of each line, converting offsets into positions.
+#[derive(Debug, Eq, PartialEq)]
+struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { starts }
+    }
+
+    fn position(&self, text: &str, offset: usize) -> Position {
+        let line = match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        Position {
+            offset,
+            line: line + 1,
+            col: text[self.starts[line]..offset].chars().count() + 1,
+        }
+    }
+
+    fn span(&self, text: &str, range: Range<usize>) -> Range<Position> {
+        self.position(text, range.start)..self.position(text, range.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rule, SyntaxNode, TokenKind};
+    use crate::node::CommentKind;
+    use std::sync::Arc;
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            "",
+            "x = 1;\n",
+            "a = [1 2 ... first row\n     3 4]; % trailing\r\n\tb = a';\n",
+            "%{\nblock\n  %{\n  nested\n  %}\n%}\nfunction y = f(x)\n  y = x.^2;\nend\n",
+            "if a, b = 1, else c = {1, 'it''s'}; end\n",
+            "hold on\npkg load signal\ns.(name) = @(x) x + 1;\n",
+            "x = = 1 # error\ny = 2 \\\n  + 3;\nz = \"tab\\t\" % done",
+        ];
+        for input in inputs.iter() {
+            let cst = SyntaxNode::parse(input);
+            println!("{:#?}", cst);
+            assert_eq!(*input, cst.to_string());
+        }
+    }
+
+    #[test]
+    fn trivia() {
+        let cst = SyntaxNode::parse("x = 1 + ... % why\n    2; # done\n");
+        let tokens = cst.tokens();
+        let kinds = tokens
+            .iter()
+            .filter(|t| t.kind().is_trivia() || t.kind() == TokenKind::Newline)
+            .map(|t| (t.kind(), t.text()))
+            .filter(|(k, _)| *k != TokenKind::Whitespace)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (TokenKind::Continuation, "... "),
+                (TokenKind::Comment(CommentKind::Line), "% why"),
+                (TokenKind::Newline, "\n"),
+                (TokenKind::Comment(CommentKind::Line), "# done"),
+                (TokenKind::Newline, "\n"),
+            ],
+            kinds
+        );
+        assert_eq!(2, cst.comments().len());
+    }
+
+    #[test]
+    fn empty_block_trivia() {
+        let cst =
+            SyntaxNode::parse("function f()\n  % TODO\nend\nif c\n  x = 1;\nelse\n  % c\nend\n");
+        let comments = cst.comments();
+        assert_eq!(
+            vec!["% TODO", "% c"],
+            comments.iter().map(|c| c.text.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(2, comments[0].span.start.line);
+        let function = cst.children().next().unwrap();
+        let body = function
+            .children()
+            .find(|n| n.kind() == Rule::block)
+            .unwrap();
+        assert!(body.tokens().iter().all(|t| t.kind() != TokenKind::Text));
+    }
+
+    #[test]
+    fn incremental_edits() {
+        let mut text = String::from(
+            "x = 1;\nfunction y = f(a)\n  y = a + 1;\nend\n\nif x, disp(x), end\n% done\n",
+        );
+        let mut cst = SyntaxNode::parse(&text);
+        let snippets = [
+            "",
+            "2",
+            "\n",
+            ";",
+            "end",
+            "if x\n",
+            "function g\n",
+            "%{\n",
+            "\n%}",
+            "[1 ...\n",
+            "'",
+            "y = f(x)\n",
+            "else",
+            "% note\n",
+        ];
+        // Simple linear congruential generator, to get the same edits on every run
+        let mut seed: u64 = 42;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 33) as usize % n.max(1)
+        };
+        for _ in 0..300 {
+            let start = random(text.len() + 1);
+            let end = (start + random(8)).min(text.len());
+            let snippet = snippets[random(snippets.len())];
+            text.replace_range(start..end, snippet);
+            cst = cst.edit(start..end, snippet);
+            assert_eq!(SyntaxNode::parse(&text), cst, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn incremental_reuse() {
+        let text = (0..10)
+            .map(|i| format!("function y = f{0}(x)\n  y = x + {0};\nend\n", i))
+            .collect::<String>();
+        let cst = SyntaxNode::parse(&text);
+        let offset = text.find("x + 5").unwrap();
+        let edited = cst.edit(offset..offset + 1, "2 * x");
+        assert_eq!(
+            SyntaxNode::parse(&text.replacen("x + 5", "2 * x + 5", 1)),
+            edited
+        );
+        let functions = |cst: &SyntaxNode| cst.children().map(|n| n.green).collect::<Vec<_>>();
+        let (before, after) = (functions(&cst), functions(&edited));
+        let shared = (0..before.len())
+            .filter(|&i| Arc::ptr_eq(&before[i], &after[i]))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 2, 3, 4, 6, 7, 8, 9, 10], shared);
+    }
+
+    #[test]
+    fn incremental_nested_edits() {
+        let mut text = String::from(
+            "function y = f(a)\n  if a\n    y = 1;\n    for i = 1:a\n      y = y * i;\n    end\n  \
+             else\n    y = 0;\n  end\nend\nfunction g\n  while true\n    disp x\n  end\n",
+        );
+        let mut cst = SyntaxNode::parse(&text);
+        let snippets = [
+            "",
+            "1",
+            "\n",
+            ";",
+            ",",
+            "end",
+            "end\n",
+            "if x\n",
+            "function h\n",
+            "x = [1\n",
+            "disp y",
+            "'",
+            "(",
+            "% c\n",
+            "...\n",
+            "else\n",
+            "%}",
+        ];
+        let mut seed: u64 = 7;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 33) as usize % n.max(1)
+        };
+        for _ in 0..500 {
+            let start = random(text.len() + 1);
+            let end = (start + random(4)).min(text.len());
+            let snippet = snippets[random(snippets.len())];
+            text.replace_range(start..end, snippet);
+            cst = cst.edit(start..end, snippet);
+            assert_eq!(SyntaxNode::parse(&text), cst, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn incremental_reuse_in_block() {
+        let body = (0..10)
+            .map(|i| format!("  y = y + {};\n", i))
+            .collect::<String>();
+        let text = format!("x = 1;\nfunction y = f(y)\n{}end\n", body);
+        let cst = SyntaxNode::parse(&text);
+        let offset = text.find("+ 5").unwrap();
+        let edited = cst.edit(offset..offset + 1, "*");
+        assert_eq!(SyntaxNode::parse(&text.replacen("+ 5", "* 5", 1)), edited);
+        let statements = |cst: &SyntaxNode| {
+            let function = cst.children().nth(1).unwrap();
+            let body = function
+                .children()
+                .find(|n| n.kind() == Rule::block)
+                .unwrap();
+            body.children().map(|n| n.green).collect::<Vec<_>>()
+        };
+        let (before, after) = (statements(&cst), statements(&edited));
+        let shared = (0..before.len())
+            .filter(|&i| Arc::ptr_eq(&before[i], &after[i]))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 2, 3, 4, 6, 7, 8, 9], shared);
+        assert!(Arc::ptr_eq(
+            &cst.children().next().unwrap().green,
+            &edited.children().next().unwrap().green
+        ));
+    }
+}
@@ -0,0 +1,443 @@
+//! Diagnostics of a document, as reported by the language server and the linter. Each diagnostic
+//! comes from a `Rule`, whose code is the diagnostic's code. The severity of rules can be changed
+//! with a `LintConfig`, and diagnostics are suppressed on lines with a `%#ok` comment, either for
+//! all rules or for the ones it lists, as in `%#ok<unused-variable,shadowed-builtin>`.
+use crate::ast::{Expr, Statement};
+use crate::node::{range_to_lsp, CommentKind, Node, Position};
+use crate::scope::{scopes, DefinitionKind, Scope};
+use crate::visit::{walk_expr, walk_stmt, Visitor};
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Builtin functions which variables shouldn't be named after.
+const BUILTINS: &[&str] = &[
+    "abs", "all", "any", "cat", "ceil", "cell", "char", "class", "cos", "cumsum", "diag", "disp",
+    "double", "eps", "error", "exp", "eye", "find", "floor", "fprintf", "Inf", "input", "int32",
+    "isempty", "length", "linspace", "log", "max", "mean", "median", "min", "mod", "NaN", "numel",
+    "ones", "pi", "plot", "printf", "prod", "rand", "repmat", "reshape", "round", "sin", "single",
+    "size", "sort", "sound", "sprintf", "sqrt", "std", "strcat", "struct", "sum", "tan", "var",
+    "warning", "zeros",
+];
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Rule {
+    SyntaxError,
+    /// `case` value already matched by a previous case of the same `switch`
+    DuplicateCase,
+    /// Variable of a function which is assigned but never read
+    UnusedVariable,
+    /// Variable named after a builtin function, which it hides
+    ShadowedBuiltin,
+    /// Statement of a function displaying its value
+    MissingSemicolonInFunction,
+    /// Variable read before any of its definitions
+    UndefinedVariable,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [
+        Self::SyntaxError,
+        Self::DuplicateCase,
+        Self::UnusedVariable,
+        Self::ShadowedBuiltin,
+        Self::MissingSemicolonInFunction,
+        Self::UndefinedVariable,
+    ];
+
+    /// Stable code of the rule, as used in `%#ok` comments and in configurations.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::SyntaxError => "syntax-error",
+            Self::DuplicateCase => "duplicate-case",
+            Self::UnusedVariable => "unused-variable",
+            Self::ShadowedBuiltin => "shadowed-builtin",
+            Self::MissingSemicolonInFunction => "missing-semicolon-in-function",
+            Self::UndefinedVariable => "undefined-variable",
+        }
+    }
+
+    pub fn default_level(&self) -> Level {
+        match self {
+            Self::SyntaxError => Level::Error,
+            Self::DuplicateCase
+            | Self::UnusedVariable
+            | Self::ShadowedBuiltin
+            | Self::UndefinedVariable => Level::Warning,
+            Self::MissingSemicolonInFunction => Level::Info,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Level {
+    Off,
+    Hint,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Hint => "hint",
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+
+    pub fn severity(&self) -> Option<DiagnosticSeverity> {
+        match self {
+            Self::Off => None,
+            Self::Hint => Some(DiagnosticSeverity::Hint),
+            Self::Info => Some(DiagnosticSeverity::Information),
+            Self::Warning => Some(DiagnosticSeverity::Warning),
+            Self::Error => Some(DiagnosticSeverity::Error),
+        }
+    }
+}
+
+/// Levels of the rules of a project, such as
+///
+/// ```toml
+/// [rules]
+/// unused-variable = "off"
+/// undefined-variable = "error"
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct LintConfig {
+    /// Levels overriding the default ones of the rules
+    pub rules: HashMap<Rule, Level>,
+}
+
+impl LintConfig {
+    pub fn level(&self, rule: Rule) -> Level {
+        self.rules
+            .get(&rule)
+            .copied()
+            .unwrap_or_else(|| rule.default_level())
+    }
+}
+
+/// Diagnostics of a document, in source order, given its text and its AST.
+pub fn get_diagnostics(text: &str, ast: Node<&Statement>, config: &LintConfig) -> Vec<Diagnostic> {
+    let mut diagnostics = Diagnostics::default();
+    diagnostics.visit_stmt(ast.clone());
+    for scope in scopes(ast) {
+        diagnostics.variables(&scope);
+    }
+    let Diagnostics {
+        mut findings,
+        suppressions,
+        ..
+    } = diagnostics;
+    findings.sort_by_key(|f| f.span.start);
+    findings
+        .into_iter()
+        .filter(|f| {
+            !suppressions.iter().any(|(line, rules)| {
+                *line == f.span.start.line
+                    && rules
+                        .as_ref()
+                        .is_none_or(|r| r.iter().any(|code| code == f.rule.code()))
+            })
+        })
+        .filter_map(|f| {
+            Some(Diagnostic::new(
+                range_to_lsp(text, f.span),
+                Some(config.level(f.rule).severity()?),
+                Some(NumberOrString::String(f.rule.code().into())),
+                Some("Octave".into()),
+                f.message,
+                None,
+                None,
+            ))
+        })
+        .collect()
+}
+
+struct Finding {
+    rule: Rule,
+    span: Range<Position>,
+    message: String,
+}
+
+/// Findings of the nodes it visits, and of the scopes it's given.
+#[derive(Default)]
+struct Diagnostics {
+    findings: Vec<Finding>,
+    /// Lines of `%#ok` comments, with the codes of the rules they suppress or `None` for all rules
+    suppressions: Vec<(usize, Option<Vec<String>>)>,
+    /// Number of functions the visited node is in
+    functions: usize,
+    /// Whether the visited statement is followed by a semicolon
+    quiet: bool,
+}
+
+impl Diagnostics {
+    fn report(&mut self, rule: Rule, span: Range<Position>, message: String) {
+        self.findings.push(Finding {
+            rule,
+            span,
+            message,
+        })
+    }
+
+    /// Reports `case` values already matched by a previous case of the same `switch`.
+    fn duplicate_cases(&mut self, cases: &[(Node<Expr>, Node<Statement>)]) {
+        let mut seen: Vec<Node<&Expr>> = vec![];
+        for value in cases.iter().flat_map(|(c, _)| c.as_ref().case_values()) {
+            if let Some(first) = seen.iter().find(|v| same_case_value(v, &value)) {
+                let message = format!(
+                    "Duplicate case value, already matched on line {}",
+                    first.span().start.line
+                );
+                self.report(Rule::DuplicateCase, value.span(), message);
+            } else {
+                seen.push(value);
+            }
+        }
+    }
+
+    /// Reports statements of functions which display their value, leaving out calls and
+    /// identifiers which may be calls to functions returning nothing.
+    fn missing_semicolon(&mut self, node: &Node<&Statement>) {
+        let displayed = match node.data {
+            Statement::Assignment(..) | Statement::AugAssignment(..) => true,
+            Statement::Expr(e) => !matches!(
+                e.data,
+                Expr::Call(..)
+                    | Expr::Command(..)
+                    | Expr::Identifier(_)
+                    | Expr::Incr(_)
+                    | Expr::Decr(_)
+                    | Expr::Error(_)
+            ),
+            _ => false,
+        };
+        if displayed {
+            self.report(
+                Rule::MissingSemicolonInFunction,
+                node.span(),
+                "Statement displays its value, terminate it with a semicolon".into(),
+            );
+        }
+    }
+
+    /// Reports misuses of the variables of a scope. Scopes using variables by name, as with
+    /// `eval`, are left alone.
+    fn variables(&mut self, scope: &Scope) {
+        let mut names: Vec<&str> = vec![];
+        for d in &scope.definitions {
+            if !names.contains(&d.name) {
+                names.push(d.name);
+            }
+        }
+        for name in names.iter().copied().filter(|n| BUILTINS.contains(n)) {
+            let first = scope.definitions.iter().find(|d| d.name == name).unwrap();
+            let message = format!("Variable {} shadows a builtin function", name);
+            self.report(Rule::ShadowedBuiltin, first.span.clone(), message);
+        }
+        if scope.dynamic {
+            return;
+        }
+        let mut unused = vec![];
+        for name in names {
+            let defined_from = scope
+                .definitions
+                .iter()
+                .filter(|d| d.name == name)
+                .map(|d| d.from)
+                .min()
+                .unwrap();
+            let mut reads = scope.reads.iter().filter(|(n, _)| *n == name).peekable();
+            match reads.peek() {
+                Some((_, span)) if span.start < defined_from => {
+                    let message = format!("Variable {} might be used before it is defined", name);
+                    self.report(Rule::UndefinedVariable, span.clone(), message);
+                }
+                Some(_) => {}
+                None => unused.push(name),
+            }
+        }
+        if !scope.function {
+            // Variables of scripts are left in the workspace of their caller
+            return;
+        }
+        for name in unused.into_iter().filter(|n| !scope.outputs.contains(n)) {
+            let assignment = scope
+                .definitions
+                .iter()
+                .find(|d| d.name == name && d.kind == DefinitionKind::Assignment);
+            if let Some(d) = assignment {
+                let message = format!("Variable {} is assigned but never used", name);
+                self.report(Rule::UnusedVariable, d.span.clone(), message);
+            }
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for Diagnostics {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        for c in node.comments() {
+            if let Some(rules) = suppressed_rules(&c.text).filter(|_| c.kind == CommentKind::Line) {
+                self.suppressions.push((c.span.start.line, rules));
+            }
+        }
+        let quiet = std::mem::replace(&mut self.quiet, false);
+        if self.functions > 0 && !quiet {
+            self.missing_semicolon(&node);
+        }
+        match *node {
+            Statement::Error(s) => self.report(Rule::SyntaxError, node.span(), s.clone()),
+            Statement::IgnoreOutput(_) => self.quiet = true,
+            Statement::Switch(_, cases, _) => self.duplicate_cases(cases),
+            Statement::Function(..) => {
+                self.functions += 1;
+                walk_stmt(self, *node);
+                self.functions -= 1;
+                return;
+            }
+            _ => {}
+        }
+        walk_stmt(self, *node)
+    }
+
+    fn visit_expr(&mut self, node: Node<&'a Expr>) {
+        if let Expr::Error(s) = *node {
+            self.report(Rule::SyntaxError, node.span(), s.clone());
+        }
+        walk_expr(self, *node)
+    }
+}
+
+/// Rules suppressed by a `%#ok` comment, `None` standing for all of them.
+fn suppressed_rules(comment: &str) -> Option<Option<Vec<String>>> {
+    let rest = comment.strip_prefix("%#ok")?;
+    match rest.strip_prefix('<') {
+        Some(rest) => {
+            let list = &rest[..rest.find('>')?];
+            Some(Some(list.split(',').map(|r| r.trim().into()).collect()))
+        }
+        None => Some(None),
+    }
+}
+
+/// Whether two `case` values match the same subjects, regardless of how strings are quoted.
+fn same_case_value(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::LitString(a), Expr::LitString(b)) => a.value == b.value,
+        (Expr::LitNumber(a), Expr::LitNumber(b)) | (Expr::LitComplex(a), Expr::LitComplex(b)) => {
+            a.value == b.value
+        }
+        (Expr::LitInteger(a), Expr::LitInteger(b)) => a.value == b.value && a.ty == b.ty,
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_diagnostics, Level, LintConfig, Rule};
+    use crate::parser::parse;
+    use lsp_types::{DiagnosticSeverity, NumberOrString};
+
+    /// `code:line` of the diagnostics of a document, with one-based lines.
+    fn codes(input: &str) -> Vec<String> {
+        get_diagnostics(input, parse(input).as_ref(), &LintConfig::default())
+            .into_iter()
+            .map(|d| match d.code {
+                Some(NumberOrString::String(code)) => {
+                    format!("{}:{}", code, d.range.start.line + 1)
+                }
+                _ => panic!("Diagnostic without code: {:?}", d),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            vec![
+                "unused-variable:2",
+                "shadowed-builtin:3",
+                "missing-semicolon-in-function:4",
+                "undefined-variable:5",
+                "duplicate-case:9",
+            ],
+            codes(
+                "function y = f(x, ~)\n\
+                 tmp = x;\n\
+                 sum = 0;\n\
+                 y = sum + x\n\
+                 z = z + 1;\n\
+                 disp(z);\n\
+                 switch x\n\
+                 case 1\n\
+                 case 1\n\
+                 end\n\
+                 end\n"
+            )
+        );
+        // Variables of scripts may be used by their caller, while `eval` may use any of them
+        assert_eq!(
+            Vec::<String>::new(),
+            codes("a = 1\nfunction f(s)\n  b = 2;\n  eval(s);\nend\n")
+        );
+        // Parameters of anonymous functions, fields and loop variables are handled
+        assert_eq!(
+            Vec::<String>::new(),
+            codes(
+                "function r = g(v)\n  k = @(t) t + 1;\n  s.t = 1;\n  r = k(s.t);\n  \
+                 for i = v, r = r + i; end\nend\n"
+            )
+        );
+        assert_eq!(vec!["syntax-error:1"], codes("x = (1 +\n"));
+    }
+
+    #[test]
+    fn levels() {
+        let input = "function f()\n  x = 1;\n  y = 2\nend\n";
+        let mut config = LintConfig::default();
+        config.rules.insert(Rule::UnusedVariable, Level::Off);
+        config
+            .rules
+            .insert(Rule::MissingSemicolonInFunction, Level::Error);
+        let diagnostics = get_diagnostics(input, parse(input).as_ref(), &config);
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(Some(DiagnosticSeverity::Error), diagnostics[0].severity);
+        assert_eq!(2, diagnostics[0].range.start.line);
+    }
+
+    #[test]
+    fn suppressions() {
+        let input = "function f()\n  x = 1 %#ok<unused-variable>\n  y = 2; %#ok\n  \
+                     sum = 3; %#ok<unused-variable, undefined-variable>\n  z = 4 % #ok\nend\n";
+        assert_eq!(
+            vec![
+                "missing-semicolon-in-function:2",
+                "shadowed-builtin:4",
+                "missing-semicolon-in-function:5",
+                "unused-variable:5",
+            ],
+            codes(input)
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Formatting of documents by printing their AST back in a given `Style`, either whole or only
+//! the top-level statements around a range. Code with syntax errors is left alone, and so is
+//! output that wouldn't parse back into the same program with the same comments.
+use crate::ast::{Expr, Statement};
+use crate::cst::{SyntaxNode, TokenKind};
+use crate::node::Node;
+use crate::parser::from_cst;
+use crate::printer::{PrintError, Style};
+use crate::sexp::stmt_to_sexp;
+use crate::visit::{walk_stmt, Visitor};
+use std::ops::Range;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum FormatError {
+    #[error("Cannot format code with syntax errors")]
+    SyntaxErrors,
+    #[error("Formatting would change the meaning or the comments of the code")]
+    Unstable,
+}
+
+impl From<PrintError> for FormatError {
+    fn from(err: PrintError) -> Self {
+        match err {
+            PrintError::SyntaxErrors => FormatError::SyntaxErrors,
+        }
+    }
+}
+
+/// Formatted text of a whole document.
+pub fn format(text: &str, style: &Style) -> Result<String, FormatError> {
+    let cst = SyntaxNode::parse(text);
+    let ast = from_cst(&cst);
+    let formatted = style.stmt_to_source(&ast)?;
+    check(&cst, &ast, &formatted)?;
+    Ok(formatted)
+}
+
+/// Formatted text of the top-level statements on the lines of `range`, along with the range of
+/// whole lines it replaces. Returns `None` when there are no statements to format there.
+pub fn format_range(
+    text: &str,
+    range: Range<usize>,
+    style: &Style,
+) -> Result<Option<(Range<usize>, String)>, FormatError> {
+    let cst = SyntaxNode::parse(text);
+    let ast = from_cst(&cst);
+    let stmts = match &ast.data {
+        Statement::Block(v) => v,
+        _ => return Ok(None),
+    };
+    // A selection of whole lines ends at the start of the next one
+    let end = if range.end > range.start && text[..range.end].ends_with('\n') {
+        range.end - 1
+    } else {
+        range.end
+    };
+    // Grow the selection until it only cuts lines between statements
+    let mut lines = line_start(text, range.start)..line_end(text, end);
+    let selected = loop {
+        let selected = stmts
+            .iter()
+            .filter(|s| !matches!(s.data, Statement::EOI) || !s.comments().is_empty())
+            .filter(|s| {
+                let extent = extent(s);
+                extent.start <= lines.end && extent.end >= lines.start
+            })
+            .collect::<Vec<_>>();
+        let grown = selected
+            .iter()
+            .map(|s| extent(s))
+            .fold(lines.clone(), |lines, e| {
+                line_start(text, e.start).min(lines.start)..line_end(text, e.end).max(lines.end)
+            });
+        if grown == lines {
+            break selected;
+        }
+        lines = grown;
+    };
+    if selected.is_empty() {
+        return Ok(None);
+    }
+    let stmts = selected.into_iter().cloned().collect::<Vec<_>>();
+    let formatted = style.block_to_source(&stmts)?;
+    let formatted = formatted.strip_suffix('\n').unwrap_or(&formatted);
+    let mut result = text.to_string();
+    result.replace_range(lines.clone(), formatted);
+    check(&cst, &ast, &result)?;
+    Ok(Some((lines, formatted.to_string())))
+}
+
+/// Checks that the formatted text has the same program and comments as the original one, with
+/// each comment attached to the same statement or block.
+fn check(cst: &SyntaxNode, ast: &Node<Statement>, formatted: &str) -> Result<(), FormatError> {
+    let formatted_cst = SyntaxNode::parse(formatted);
+    let formatted_ast = from_cst(&formatted_cst);
+    if comments(cst) == comments(&formatted_cst)
+        && stmt_to_sexp(ast) == stmt_to_sexp(&formatted_ast)
+        && Owners::of(ast) == Owners::of(&formatted_ast)
+    {
+        Ok(())
+    } else {
+        Err(FormatError::Unstable)
+    }
+}
+
+/// Comments attached to the statements and blocks of an AST, along with the index of their node
+/// in the order of traversal, which is the same for trees with the same structure.
+#[derive(Default)]
+struct Owners {
+    count: usize,
+    comments: Vec<(usize, String)>,
+}
+
+impl Owners {
+    fn of(ast: &Node<Statement>) -> Vec<(usize, String)> {
+        let mut owners = Self::default();
+        owners.visit_stmt(ast.as_ref());
+        owners.comments
+    }
+}
+
+impl<'a> Visitor<'a> for Owners {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        for c in node.comments() {
+            self.comments.push((self.count, c.text.clone()));
+        }
+        self.count += 1;
+        walk_stmt(self, node.data);
+    }
+
+    fn visit_expr(&mut self, _node: Node<&'a Expr>) {}
+}
+
+/// Text of the comments of a tree, including the text ignored after `...` continuations.
+fn comments(cst: &SyntaxNode) -> Vec<String> {
+    cst.tokens()
+        .iter()
+        .filter_map(|t| match t.kind() {
+            TokenKind::Comment(_) => Some(t.text()),
+            TokenKind::Continuation => t
+                .text()
+                .strip_prefix("...")
+                .map(str::trim)
+                .filter(|s| !s.is_empty()),
+            _ => None,
+        })
+        .map(Into::into)
+        .collect()
+}
+
+/// Byte range of a statement along with its comments.
+fn extent(stmt: &Node<Statement>) -> Range<usize> {
+    let range = stmt.range();
+    stmt.comments().iter().fold(range, |range, c| {
+        range.start.min(c.span.start.offset)..range.end.max(c.span.end.offset)
+    })
+}
+
+fn line_start(text: &str, offset: usize) -> usize {
+    text[..offset].rfind('\n').map_or(0, |i| i + 1)
+}
+
+/// Offset of the newline ending the line, or of the end of the text.
+fn line_end(text: &str, offset: usize) -> usize {
+    text[offset..].find('\n').map_or(text.len(), |i| offset + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, format, format_range, FormatError};
+    use crate::cst::SyntaxNode;
+    use crate::parser::from_cst;
+    use crate::printer::{EndKeyword, MatrixSeparator, Style};
+
+    const SOURCE: &str = "% Sums\n\
+                          function s = total(x)\n\
+                          s=0; % accumulator\n\
+                          for v=x , s=s+v; end\n\
+                          endfunction\n\n\n\
+                          m = [1,2 ;3 , 4]; if any(m(:)>2) disp('big'), end\n";
+
+    #[test]
+    fn document() {
+        assert_eq!(
+            Ok("% Sums
+function s = total(x)
+  s = 0; % accumulator
+  for v = x
+    s = s + v;
+  end
+end
+
+m = [1, 2; 3, 4];
+if any(m(:) > 2)
+  disp('big')
+end
+"
+            .to_string()),
+            format(SOURCE, &Style::default())
+        );
+        let style = Style {
+            indent_width: 4,
+            tabs: false,
+            operator_spacing: false,
+            matrix_separator: MatrixSeparator::Space,
+            end_keyword: EndKeyword::Specific,
+        };
+        assert_eq!(
+            Ok("% Sums
+function s = total(x)
+    s = 0; % accumulator
+    for v = x
+        s = s+v;
+    endfor
+endfunction
+
+m = [1 2; 3 4];
+if any(m(:)>2)
+    disp('big')
+endif
+"
+            .to_string()),
+            format(SOURCE, &style)
+        );
+        assert_eq!(
+            Err(FormatError::SyntaxErrors),
+            format("x = (1 +\n", &Style::default())
+        );
+    }
+
+    #[test]
+    fn spelling() {
+        let source = "if a ~= b && ~c, hold on, end\n\
+                      x = [1e-3, -6.02e23, 0b1010, 0x1F, 2.5e-3j];\n\
+                      pkg load signal 'a b'\n";
+        assert_eq!(
+            Ok("if a ~= b && ~c\n  hold on\nend\n\
+                x = [1e-3, -6.02e23, 0b1010, 0x1F, 2.5e-3j];\n\
+                pkg load signal 'a b'\n"
+                .to_string()),
+            format(source, &Style::default())
+        );
+        assert_eq!(
+            Ok("x = a != !b;\n".to_string()),
+            format("x = a!=!b;", &Style::default())
+        );
+        // Comments after continuations would be lost
+        assert_eq!(
+            Err(FormatError::Unstable),
+            format("x = [1 2 ... note\n     3];\n", &Style::default())
+        );
+        assert_eq!(
+            Ok("x = [1, 2, 3];\n".to_string()),
+            format("x = [1 2 ...\n     3];\n", &Style::default())
+        );
+    }
+
+    #[test]
+    fn block_comments() {
+        let style = Style::default();
+        assert_eq!(
+            Ok("function f\n  % TODO implement\nend\n".to_string()),
+            format("function f()\n  % TODO implement\nend", &style)
+        );
+        assert_eq!(
+            Ok("if c\n  x = 1;\nelse\n  % only comment\nend\n".to_string()),
+            format("if c\n  x=1;\nelse\n  % only comment\nend", &style)
+        );
+        assert_eq!(
+            Ok("for i = 1:3\n  x = i;\n\n  % after\nend\ny = 2;\n".to_string()),
+            format("for i=1:3\n  x=i;\n\n  % after\nend\ny=2;", &style)
+        );
+        assert_eq!(
+            Ok("if c\n  x = 1;\n  % before else\nelse\n  y = 2;\nend\n".to_string()),
+            format("if c, x=1;\n  % before else\nelse y=2;\nend", &style)
+        );
+        assert_eq!(
+            Ok("switch x\n  case 1\n    % one\n  otherwise\n    % other\nend\n".to_string()),
+            format("switch x\ncase 1\n% one\notherwise % other\nend", &style)
+        );
+    }
+
+    #[test]
+    fn moved_comments() {
+        // The comments are in the same order, but the one ending the loop would end up before the
+        // statement following it
+        let source = "for i = 1:3\n  x = i;\n  % after\nend\ny = 2;\n";
+        let cst = SyntaxNode::parse(source);
+        let ast = from_cst(&cst);
+        assert_eq!(Ok(()), check(&cst, &ast, source));
+        assert_eq!(
+            Err(FormatError::Unstable),
+            check(&cst, &ast, "for i = 1:3\n  x = i;\nend\n% after\ny = 2;\n")
+        );
+        assert_eq!(
+            Err(FormatError::Unstable),
+            check(
+                &cst,
+                &ast,
+                "for i = 1:3\n  % after\n  x = i;\nend\ny = 2;\n"
+            )
+        );
+    }
+
+    #[test]
+    fn range() {
+        let style = Style::default();
+        // Selecting a line of the function formats it whole, along with its comments
+        let start = SOURCE.find("s=0").unwrap();
+        let (range, text) = format_range(SOURCE, start..start + 1, &style)
+            .unwrap()
+            .unwrap();
+        assert_eq!(0..SOURCE.find("\n\n").unwrap(), range);
+        assert!(text.starts_with("% Sums\nfunction s = total(x)\n  s = 0;"));
+        // Both statements on the last line are formatted
+        let start = SOURCE.find("disp").unwrap();
+        let (range, text) = format_range(SOURCE, start..start, &style).unwrap().unwrap();
+        assert_eq!(SOURCE.find("m = ").unwrap()..SOURCE.len() - 1, range);
+        assert_eq!(
+            "m = [1, 2; 3, 4];\nif any(m(:) > 2)\n  disp('big')\nend",
+            text
+        );
+        let start = SOURCE.find("m = ").unwrap();
+        assert_eq!(
+            Ok(Some((range.clone(), text))),
+            format_range(SOURCE, start..SOURCE.len(), &style)
+        );
+        // Blank lines have nothing to format
+        let start = SOURCE.find("\n\n").unwrap() + 1;
+        assert_eq!(Ok(None), format_range(SOURCE, start..start, &style));
+    }
+}
@@ -1,9 +1,16 @@
 #[macro_use]
 extern crate pest_derive;
-#[macro_use]
-extern crate lazy_static;
 
+pub mod cst;
 pub mod node;
 mod value;
 pub mod parser;
-pub mod ast;
\ No newline at end of file
+pub mod ast;
+pub mod visit;
+pub mod sexp;
+pub mod printer;
+pub mod format;
+pub mod diagnostics;
+mod scope;
+#[cfg(feature = "config")]
+pub mod config;
@@ -1,47 +1,112 @@
-use crate::ast::{Expr, Statement};
+use crate::ast::{Expr, Op, Statement};
+use crate::visit::{walk_expr, walk_stmt, Visitor};
 use lsp_types as lsp;
 use std::ops::{Deref, Range};
 
-pub trait Tree: Clone {
-    type Item;
-    fn children(&self) -> Vec<Self::Item>;
-}
-
-impl<'a, T: Tree> Tree for &'a T {
-    type Item = T::Item;
-
-    fn children(&self) -> Vec<Self::Item> {
-        T::children(self)
-    }
-}
-
+/// Position in the source of a document. Positions are ordered by their byte offset.
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Position {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    /// 1-based line number
     pub line: usize,
+    /// 1-based column, counted in characters
     pub col: usize,
 }
 
-impl From<lsp::Position> for Position {
-    fn from(s: lsp::Position) -> Self {
+impl Position {
+    /// Position within `text` of an LSP position, whose character is counted in UTF-16 code
+    /// units. Positions past the end of a line are clamped to the end of that line, and positions
+    /// past the last line to the end of the text.
+    pub fn from_lsp(text: &str, pos: lsp::Position) -> Self {
+        let line_start = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .nth(pos.line as usize);
+        let line_start = match line_start {
+            Some(start) => start,
+            None => return Self::at_offset(text, text.len()),
+        };
+        let line = &text[line_start..];
+        let line = &line[..line.find(['\r', '\n']).unwrap_or(line.len())];
+        let mut units = 0;
+        let len = line
+            .char_indices()
+            .find(|(_, c)| {
+                units += c.len_utf16() as u64;
+                units > pos.character
+            })
+            .map_or(line.len(), |(i, _)| i);
         Self {
-            line: (s.line + 1) as usize,
-            col: (s.character + 1) as usize,
+            offset: line_start + len,
+            line: pos.line as usize + 1,
+            col: line[..len].chars().count() + 1,
         }
     }
-}
 
-impl From<Position> for lsp::Position {
-    fn from(p: Position) -> Self {
+    /// Position of the given byte offset within `text`.
+    pub fn at_offset(text: &str, offset: usize) -> Self {
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
         Self {
-            line: (p.line - 1) as u64,
-            character: (p.col - 1) as u64,
+            offset,
+            line: text[..line_start].matches('\n').count() + 1,
+            col: text[line_start..offset].chars().count() + 1,
+        }
+    }
+
+    /// LSP position of this position within `text`, counting characters in UTF-16 code units.
+    pub fn to_lsp(self, text: &str) -> lsp::Position {
+        let line_start = text[..self.offset].rfind('\n').map_or(0, |i| i + 1);
+        lsp::Position {
+            line: (self.line - 1) as u64,
+            character: text[line_start..self.offset].encode_utf16().count() as u64,
+        }
+    }
+}
+
+/// LSP range of a span within `text`, counting characters in UTF-16 code units.
+pub fn range_to_lsp(text: &str, span: Range<Position>) -> lsp::Range {
+    lsp::Range {
+        start: span.start.to_lsp(text),
+        end: span.end.to_lsp(text),
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum CommentKind {
+    /// `%` or `#` comment running until the end of the line
+    Line,
+    /// `%{ ... %}` or `#{ ... #}` comment, possibly spanning several lines
+    Block,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Comment {
+    pub span: Range<Position>,
+    pub kind: CommentKind,
+    /// Source text of the comment, including its delimiters
+    pub text: String,
+}
+
+impl Comment {
+    /// Text of the comment with its delimiters stripped.
+    pub fn content(&self) -> &str {
+        match self.kind {
+            CommentKind::Line => self.text[1..].trim_start_matches(['%', '#']),
+            CommentKind::Block => self.text[2..self.text.len() - 2].trim_matches(['\r', '\n']),
         }
     }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Node<T> {
     pub(crate) span: Range<Position>,
+    /// Comments attached to this node as trivia, either preceding it or trailing it on its last line.
+    /// Blocks hold the comments following their last statement.
+    pub(crate) comments: Vec<Comment>,
     pub(crate) data: T,
 }
 
@@ -49,6 +114,25 @@ impl<T> Node<T> {
     pub fn span(&self) -> Range<Position> {
         self.span.clone()
     }
+
+    /// Byte range of the node in the source.
+    pub fn range(&self) -> Range<usize> {
+        self.span.start.offset..self.span.end.offset
+    }
+
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    pub fn leading_comments(&self) -> impl Iterator<Item = &Comment> {
+        let start = self.span.start;
+        self.comments.iter().filter(move |c| c.span.start < start)
+    }
+
+    pub fn trailing_comments(&self) -> impl Iterator<Item = &Comment> {
+        let start = self.span.start;
+        self.comments.iter().filter(move |c| c.span.start >= start)
+    }
 }
 
 impl<T> Deref for Node<T> {
@@ -63,6 +147,7 @@ impl<T> Node<T> {
     pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Node<U> {
         Node {
             span: self.span,
+            comments: self.comments,
             data: f(self.data),
         }
     }
@@ -70,6 +155,7 @@ impl<T> Node<T> {
     pub fn as_ref(&self) -> Node<&T> {
         Node {
             span: self.span.clone(),
+            comments: self.comments.clone(),
             data: &self.data,
         }
     }
@@ -79,6 +165,7 @@ impl<T, B: Deref<Target = T>> Node<B> {
     pub fn as_deref(&self) -> Node<&T> {
         Node {
             span: self.span.clone(),
+            comments: self.comments.clone(),
             data: self.data.deref(),
         }
     }
@@ -95,10 +182,12 @@ impl<T, E> Node<Result<T, E>> {
         match self.data {
             Ok(data) => Ok(Node {
                 span: self.span,
+                comments: self.comments,
                 data,
             }),
             Err(data) => Err(Node {
                 span: self.span,
+                comments: self.comments,
                 data,
             }),
         }
@@ -120,6 +209,7 @@ impl<T> Node<Option<T>> {
         match self.data {
             Some(data) => Some(Node {
                 span: self.span,
+                comments: self.comments,
                 data,
             }),
             None => None,
@@ -128,36 +218,20 @@ impl<T> Node<Option<T>> {
 }
 
 impl Node<&Expr> {
+    /// Innermost expression or name containing the given position.
     pub fn at_pos(&self, pos: Position) -> Option<Node<Expr>> {
-        if self.span.contains(&pos) {
-            Some(match self.data {
-                Expr::Error(_) | Expr::Identifier(_) | Expr::LitNumber(_) | Expr::LitString(_) => Some(self.clone().map(Clone::clone)),
-                Expr::Matrix(m) => m.iter().filter_map(|n| n.as_ref().at_pos(pos)).next(),
-                Expr::Op(_, a, b) => a.as_deref().at_pos(pos).or_else(|| b.as_deref().at_pos(pos)),
-                Expr::Call(c, v) => c.as_deref().at_pos(pos).or_else(|| v.iter().filter_map(|n| n.as_ref().at_pos(pos)).next()),
-                Expr::Decr(e) | Expr::Incr(e) => e.as_deref().at_pos(pos),
-                Expr::Range(s, st, e) => s.as_deref().at_pos(pos).or_else(|| st.as_ref().and_then(|n| n.as_deref().at_pos(pos))).or_else(|| e.as_deref().at_pos(pos)),
-            }.unwrap_or(self.clone().map(Clone::clone)))
-        } else {
-            None
-        }
+        let mut finder = AtPos { pos, found: None };
+        finder.visit_expr(self.clone());
+        finder.found.map(Found::into_expr)
     }
 }
 
 impl Node<&Statement> {
+    /// Innermost expression or name containing the given position.
     pub fn at_pos(&self, pos: Position) -> Option<Node<Expr>> {
-        if self.span.contains(&pos) {
-            match self.data {
-                Statement::Expr(e) => e.as_ref().at_pos(pos),
-                Statement::Assignment(_, e) => e.as_ref().at_pos(pos),
-                Statement::AugAssignment(_, _, e) => e.as_ref().at_pos(pos),
-                Statement::Block(v) => v.iter().filter_map(|n| n.as_ref().at_pos(pos)).next(),
-                Statement::IgnoreOutput(e) => e.as_deref().at_pos(pos),
-                Statement::EOI | Statement::Error(_) => None,
-            }
-        } else {
-            None
-        }
+        let mut finder = AtPos { pos, found: None };
+        finder.visit_stmt(self.clone());
+        finder.found.map(Found::into_expr)
     }
 }
 
@@ -166,3 +240,50 @@ impl Node<Statement> {
         self.as_ref().at_pos(pos)
     }
 }
+
+enum Found<'a> {
+    Expr(Node<&'a Expr>),
+    Name(Node<&'a String>),
+}
+
+impl Found<'_> {
+    fn into_expr(self) -> Node<Expr> {
+        match self {
+            Self::Expr(n) => n.map(Clone::clone),
+            Self::Name(n) => n.map(|name| Expr::Identifier(name.clone())),
+        }
+    }
+}
+
+/// Keeps the last visited node containing a position, which is the innermost one as children are
+/// visited after their parent.
+struct AtPos<'a> {
+    pos: Position,
+    found: Option<Found<'a>>,
+}
+
+impl<'a> Visitor<'a> for AtPos<'a> {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        if node.span.contains(&self.pos) {
+            walk_stmt(self, node.data)
+        }
+    }
+
+    fn visit_expr(&mut self, node: Node<&'a Expr>) {
+        if node.span.contains(&self.pos) {
+            self.found = Some(Found::Expr(node.clone()));
+            match node.data {
+                // Field names are only meaningful along with the struct they're accessed from
+                Expr::Op(Op::Access, a, _) => self.visit_expr(a.as_deref()),
+                Expr::Handle(_) => {}
+                data => walk_expr(self, data),
+            }
+        }
+    }
+
+    fn visit_name(&mut self, node: Node<&'a String>) {
+        if node.span.contains(&self.pos) {
+            self.found = Some(Found::Name(node));
+        }
+    }
+}
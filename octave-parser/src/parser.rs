@@ -1,188 +1,1232 @@
 use crate::{
-    ast::{Expr, Op, Statement},
-    node::{Node, Position},
+    ast::{Expr, IntegerLiteral, Negation, NumberLiteral, Op, Statement, StringLiteral, UnaryOp},
+    cst::{SyntaxElement, SyntaxNode, TokenKind},
+    node::{Comment, Node, Position},
     value::Matrix,
+    visit::{walk_expr, walk_expr_mut, walk_stmt, walk_stmt_mut, Visitor, VisitorMut},
 };
-use pest::{
-    iterators::{Pair, Pairs},
-    prec_climber::{Assoc, Operator, PrecClimber},
-    Parser, Span,
+use octave_typesystem::IntegerType;
+use pest::Parser;
+use std::{
+    borrow::BorrowMut,
+    collections::{BTreeSet, HashMap, HashSet},
+    iter::Peekable,
+    ops::Range,
 };
-use std::{collections::HashSet, ops::Range};
 
 #[derive(Copy, Clone, Debug, Parser)]
 #[grammar = "grammar.pest"]
 pub struct OctaveParser;
 
 pub fn parse(input: &str) -> Node<Statement> {
-    OctaveParser::parse(Rule::toplevel, input)
-        .map(|pairs: Pairs<Rule>| process_stmt(pairs.into_iter().next().unwrap()))
-        .unwrap_or_else(|e: pest::error::Error<Rule>| Node {
-            span: Position { line: 1, col: 1 }..Position {
-                line: 1,
-                col: input.lines().next().unwrap().len(),
-            },
-            data: Statement::Error(format!(
-                "Parse error: {}",
-                match e.variant {
-                    pest::error::ErrorVariant::CustomError { message } => message,
-                    pest::error::ErrorVariant::ParsingError {
-                        negatives,
-                        positives,
-                    } => format!("Unexpected {:?}, expected {:?}", negatives, positives),
+    from_cst(&SyntaxNode::parse(input))
+}
+
+/// Builds the AST of a document from its concrete syntax tree.
+pub fn from_cst(cst: &SyntaxNode) -> Node<Statement> {
+    let mut reuse = Reuse::default();
+    let ast = process_stmt(cst.clone(), &mut reuse);
+    resolve(cst, ast, reuse)
+}
+
+/// Builds the AST of a document from its concrete syntax tree after an edit, given the tree and
+/// the AST of the document before it. Statements whose tree is shared with the previous one keep
+/// their node from the previous AST, moved to their new position, so that only the statements
+/// around the edit are built again.
+pub fn from_edit(previous: &SyntaxNode, ast: Node<Statement>, cst: &SyntaxNode) -> Node<Statement> {
+    let mut reuse = Reuse::default();
+    reuse.collect(previous, ast, cst);
+    let ast = process_stmt(cst.clone(), &mut reuse);
+    resolve(cst, ast, reuse)
+}
+
+/// Resolves indexing in a freshly built AST and attaches the comments of the document to it.
+fn resolve(cst: &SyntaxNode, mut ast: Node<Statement>, mut reuse: Reuse) -> Node<Statement> {
+    ResolveIndexing {
+        cst,
+        lists: vec![cst.clone()],
+        vars: HashSet::new(),
+        reuse: &mut reuse,
+    }
+    .visit_stmt_mut(&mut ast.data);
+    let comments = match reuse.reused.is_empty() {
+        true => cst.comments(),
+        false => {
+            let mut comments = vec![];
+            comments_outside(cst, &reuse.reused, &mut comments);
+            comments
+        }
+    };
+    attach_block(
+        &mut ast,
+        &mut comments.into_iter().peekable(),
+        &reuse.reused,
+    );
+    ast
+}
+
+/// Distributes comments, in source order, to the statements they precede or trail on the same
+/// line, and to the blocks they end. Reused statements already hold the comments within them,
+/// which are left out of `comments`.
+fn attach_comments<I: Iterator<Item = Comment>>(
+    stmt: &mut Statement,
+    comments: &mut Peekable<I>,
+    reused: &HashSet<usize>,
+) {
+    match stmt {
+        Statement::Block(v) => {
+            for i in 0..v.len() {
+                let next = v.get(i + 1).map(|n| n.span.start);
+                let node = &mut v[i];
+                let within = std::mem::take(&mut node.comments);
+                while let Some(c) = comments.next_if(|c| c.span.start < node.span.start) {
+                    node.comments.push(c);
+                }
+                node.comments.extend(within);
+                if !reused.contains(&node.span.start.offset) {
+                    // Comments on the first line of a multi-line statement (e.g. after an `if`
+                    // condition) belong to that statement rather than to its first child
+                    if node.span.end.line > node.span.start.line {
+                        while let Some(c) =
+                            comments.next_if(|c| c.span.start.line == node.span.start.line)
+                        {
+                            node.comments.push(c);
+                        }
+                    }
+                    attach_comments(&mut node.data, comments, reused);
+                }
+                while let Some(c) = comments.next_if(|c| {
+                    c.span.start < node.span.end
+                        || c.span.start.line == node.span.end.line
+                            && next.is_none_or(|n| c.span.start < n)
+                }) {
+                    node.comments.push(c);
+                }
+            }
+        }
+        Statement::IgnoreOutput(s) => attach_comments(&mut s.data, comments, reused),
+        Statement::If(branches, otherwise) | Statement::Switch(_, branches, otherwise) => {
+            for (_, b) in branches {
+                attach_block(b, comments, reused);
+            }
+            if let Some(b) = otherwise {
+                attach_block(b, comments, reused);
+            }
+        }
+        Statement::Try(b, _, c) => {
+            attach_block(b, comments, reused);
+            if let Some(c) = c {
+                attach_block(c, comments, reused);
+            }
+        }
+        Statement::UnwindProtect(b, c) => {
+            attach_block(b, comments, reused);
+            attach_block(c, comments, reused);
+        }
+        Statement::Function(_, _, _, b)
+        | Statement::For(_, _, b)
+        | Statement::While(_, b)
+        | Statement::DoUntil(b, _) => attach_block(b, comments, reused),
+        _ => {}
+    }
+}
+
+/// Attaches comments to the statements of a block, and the ones following its last statement up
+/// to the end of the block, including those of an empty block, to the block itself.
+fn attach_block<I: Iterator<Item = Comment>, S: BorrowMut<Statement>>(
+    block: &mut Node<S>,
+    comments: &mut Peekable<I>,
+    reused: &HashSet<usize>,
+) {
+    attach_comments(block.data.borrow_mut(), comments, reused);
+    while let Some(c) = comments.next_if(|c| c.span.start < block.span.end) {
+        block.comments.push(c);
+    }
+}
+
+/// Comments of a tree outside of the reused statements, in source order.
+fn comments_outside(node: &SyntaxNode, reused: &HashSet<usize>, comments: &mut Vec<Comment>) {
+    for e in node.elements() {
+        match e {
+            SyntaxElement::Node(n) if is_list(node) && reused.contains(&n.range().start) => {}
+            SyntaxElement::Node(n) => comments_outside(&n, reused, comments),
+            SyntaxElement::Token(t) => {
+                if let TokenKind::Comment(kind) = t.kind() {
+                    comments.push(Comment {
+                        span: t.span(),
+                        kind,
+                        text: t.text().into(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Turns calls of variables bound earlier in their scope into indexing, as `x(1)` can only be
+/// told apart from a function call by knowing whether `x` is a variable. Likewise, command syntax
+/// never applies to variables, so `x -1` is parsed again as the expression `x - 1` when `x` is one.
+/// Reused statements were resolved already, and are only built and resolved again if they use
+/// variables that are bound differently than in the previous AST.
+struct ResolveIndexing<'a> {
+    /// Tree of the whole document, to parse commands again from
+    cst: &'a SyntaxNode,
+    /// Trees of the lists of statements left to visit in the statement being visited, last first,
+    /// to build reused statements again from. They are only known when statements are reused.
+    lists: Vec<SyntaxNode>,
+    vars: HashSet<String>,
+    reuse: &'a mut Reuse,
+}
+
+impl VisitorMut for ResolveIndexing<'_> {
+    fn visit_stmt_mut(&mut self, stmt: &mut Statement) {
+        match stmt {
+            Statement::Block(v) => {
+                let list = self.lists.pop();
+                let mut nodes = list.iter().flat_map(SyntaxNode::children);
+                // Variables bound before the reused statement in the previous AST, while they
+                // differ from the current ones
+                let mut previous: Option<HashSet<String>> = None;
+                for s in v {
+                    let node = nodes.next();
+                    let offset = s.span.start.offset;
+                    if !self.reuse.reused.contains(&offset) {
+                        self.visit_within(node, &mut s.data);
+                        continue;
+                    }
+                    if let Some(vars) = self.reuse.bound.remove(&offset) {
+                        previous = Some(vars).filter(|vars| *vars != self.vars);
+                    }
+                    let previous_vars = match &mut previous {
+                        Some(vars) => vars,
+                        None => {
+                            bind(s, &mut self.vars);
+                            continue;
+                        }
+                    };
+                    let changed = previous_vars
+                        .symmetric_difference(&self.vars)
+                        .cloned()
+                        .collect();
+                    let mut mentions = Mentions {
+                        names: &changed,
+                        found: false,
+                    };
+                    mentions.visit_stmt(s.as_ref());
+                    bind(s, previous_vars);
+                    match node {
+                        Some(node) if mentions.found => {
+                            *s = process_stmt(node.clone(), &mut Reuse::default());
+                            self.reuse.reused.remove(&offset);
+                            self.visit_within(Some(node), &mut s.data);
+                        }
+                        _ => bind(s, &mut self.vars),
+                    }
+                    if *previous_vars == self.vars {
+                        previous = None;
+                    }
+                }
+            }
+            Statement::Expr(e) if matches!(&e.data, Expr::Command(c, _) if self.is_var(c)) => {
+                if let Some(node) = self.cst.parse_range(Rule::expr, e.range()) {
+                    e.data = process_expr(node).data;
+                }
+                self.visit_expr_mut(&mut e.data);
+            }
+            Statement::Assignment(t, e) => {
+                self.visit_expr_mut(&mut e.data);
+                self.visit_expr_mut(&mut t.data);
+                match (&t.data, &e.data) {
+                    // Variables holding function handles are called rather than indexed
+                    (Expr::Identifier(name), Expr::AnonFunction(..))
+                    | (Expr::Identifier(name), Expr::Handle(_)) => {
+                        self.vars.remove(name);
+                    }
+                    (t, _) => {
+                        if let Some(name) = t.root_name() {
+                            self.vars.insert(name.to_string());
+                        }
+                    }
+                }
+            }
+            Statement::Try(b, err, c) => {
+                self.visit_stmt_mut(&mut b.data);
+                if let Some(err) = err {
+                    self.vars.insert(err.data.clone());
+                }
+                if let Some(c) = c {
+                    self.visit_stmt_mut(&mut c.data);
+                }
+            }
+            Statement::Function(outputs, _, inputs, b) => {
+                let vars = outputs
+                    .iter()
+                    .chain(inputs.iter())
+                    .map(|n| n.data.clone())
+                    .collect();
+                ResolveIndexing {
+                    cst: self.cst,
+                    lists: std::mem::take(&mut self.lists),
+                    vars,
+                    reuse: &mut *self.reuse,
+                }
+                .visit_stmt_mut(&mut b.data);
+            }
+            Statement::For(i, e, b) => {
+                self.visit_expr_mut(&mut e.data);
+                self.vars.insert(i.clone());
+                self.visit_stmt_mut(&mut b.data);
+            }
+            _ => walk_stmt_mut(self, stmt),
+        }
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if matches!(expr, Expr::Call(c, _) if self.is_var(c)) {
+            if let Expr::Call(c, args) = std::mem::replace(expr, Expr::Colon) {
+                *expr = Expr::Index(c, args);
+            }
+        }
+        match expr {
+            Expr::AnonFunction(params, body) => {
+                let mut vars = self.vars.clone();
+                for p in params.iter() {
+                    vars.insert(p.data.clone());
+                }
+                ResolveIndexing {
+                    cst: self.cst,
+                    lists: vec![],
+                    vars,
+                    reuse: &mut *self.reuse,
+                }
+                .visit_expr_mut(&mut body.data);
+            }
+            _ => walk_expr_mut(self, expr),
+        }
+    }
+}
+
+impl ResolveIndexing<'_> {
+    /// Visits a statement of a list, given its tree when known.
+    fn visit_within(&mut self, node: Option<SyntaxNode>, stmt: &mut Statement) {
+        let mut lists = vec![];
+        if let Some(node) = node.filter(|_| !self.reuse.reused.is_empty()) {
+            nested_lists(node, &mut lists);
+            lists.reverse();
+        }
+        let outer = std::mem::replace(&mut self.lists, lists);
+        self.visit_stmt_mut(stmt);
+        self.lists = outer;
+    }
+
+    fn is_var(&self, expr: &Expr) -> bool {
+        matches!(expr, Expr::Identifier(i) if self.vars.contains(i))
+    }
+}
+
+/// Statements of the previous AST of a document that are reused for the new one.
+#[derive(Debug, Default)]
+struct Reuse {
+    /// Statements of the previous AST, already moved to their new position, by their new start
+    /// offset
+    nodes: HashMap<usize, Node<Statement>>,
+    /// Start offsets of the statements taken from `nodes`
+    reused: HashSet<usize>,
+    /// Variables bound in the previous AST before each run of consecutive reused statements, by
+    /// the new start offset of its first statement
+    bound: HashMap<usize, HashSet<String>>,
+}
+
+impl Reuse {
+    /// Takes the statements of the previous AST of a document whose tree is shared with the new
+    /// tree, in the lists of statements of both trees that correspond.
+    fn collect(&mut self, previous: &SyntaxNode, mut ast: Node<Statement>, cst: &SyntaxNode) {
+        let mut lists = vec![];
+        let texts = (previous.text(), cst.text());
+        pair_lists(previous.clone(), cst.clone(), texts, None, &mut lists);
+        // Previous and new start of the reused statements of each list
+        let lists = lists
+            .into_iter()
+            .map(|list| {
+                let moves = list
+                    .statements
+                    .into_iter()
+                    .map(|(old, new)| new.map(|new| (old.span().start, new.span().start)))
+                    .collect::<Vec<_>>();
+                (list.range, moves, list.after)
+            })
+            .collect::<Vec<_>>();
+
+        // Statements inserted by the edit end a run even when no previous statement changed
+        let mut runs = vec![];
+        for (_, moves, after) in &lists {
+            for (i, m) in moves.iter().enumerate() {
+                match m {
+                    Some((from, to)) if i == 0 || i == *after || moves[i - 1].is_none() => {
+                        runs.push((from.offset, to.offset))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        runs.sort_unstable();
+        let mut bound = Bound {
+            vars: HashSet::new(),
+            until: runs.iter().rev().map(|(from, _)| *from).collect(),
+            found: vec![],
+        };
+        bound.visit_stmt(ast.as_ref());
+        for ((_, to), vars) in runs.into_iter().zip(bound.found) {
+            self.bound.insert(to, vars);
+        }
+
+        // Lists deeper in the tree first, as they're within the changed statements of the others
+        let root = ast.range();
+        for (range, moves, _) in lists.into_iter().rev() {
+            let old = match &mut ast.data {
+                Statement::Block(v) if range == root => std::mem::take(v),
+                stmt => take_list(stmt, &range),
+            };
+            if old.len() != moves.len() {
+                continue;
+            }
+            for (mut node, m) in old.into_iter().zip(moves) {
+                if let Some((from, to)) = m {
+                    let span = node.span.clone();
+                    node.comments
+                        .retain(|c| span.start <= c.span.start && c.span.start < span.end);
+                    if from != to {
+                        let mut shift = Shift { from, to };
+                        shift.visit_span_mut(&mut node.span, &mut node.comments);
+                        shift.visit_stmt_mut(&mut node.data);
+                    }
+                    self.nodes.insert(to.offset, node);
+                }
+            }
+        }
+    }
+
+    /// Previous node of a statement of the new tree, if it's reused.
+    fn take(&mut self, node: &SyntaxNode) -> Option<Node<Statement>> {
+        let offset = node.range().start;
+        let stmt = self.nodes.remove(&offset)?;
+        self.reused.insert(offset);
+        Some(stmt)
+    }
+}
+
+/// A list of statements of a previous tree, paired with the corresponding list of a new tree.
+struct PairedList {
+    /// Range of the previous list
+    range: Range<usize>,
+    /// Statements of the previous list, with the statement of the new list sharing their tree.
+    /// Only the statements before and after the changes that keep their comments are paired.
+    statements: Vec<(SyntaxNode, Option<SyntaxNode>)>,
+    /// Index of the first statement after the changes
+    after: usize,
+}
+
+/// Lists of statements of a previous and a new tree that correspond, from the given ones down to
+/// the innermost list containing the changes, given the previous and new text of the document.
+/// Lists are only paired further within a single changed statement, given its first lines in both
+/// trees.
+fn pair_lists(
+    old: SyntaxNode,
+    new: SyntaxNode,
+    texts: (&str, &str),
+    first_lines: Option<(usize, usize)>,
+    lists: &mut Vec<PairedList>,
+) {
+    let range = old.range();
+    let old = old.children().collect::<Vec<_>>();
+    let new = new.children().collect::<Vec<_>>();
+    let shared = |(a, b): (&SyntaxNode, &SyntaxNode)| a.shares_tree(b);
+    let prefix = old.iter().zip(&new).take_while(|&p| shared(p)).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|&p| shared(p))
+        .count();
+
+    // Lists within the single changed statement, with its first lines
+    let mut nested = None;
+    if let ([old], [new]) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    ) {
+        let mut changed = (vec![], vec![]);
+        nested_lists(old.clone(), &mut changed.0);
+        nested_lists(new.clone(), &mut changed.1);
+        nested = Some((changed, (old.span().start.line, new.span().start.line)));
+    }
+    let after = old.len() - suffix;
+    let keeps = (
+        keeps_comments(&old, texts.0, first_lines.map(|l| l.0)),
+        keeps_comments(&new, texts.1, first_lines.map(|l| l.1)),
+    );
+    let statements = old
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let j = match i {
+                i if i < prefix => Some(i),
+                i if i >= after => Some(i + new.len() - old.len()),
+                _ => None,
+            };
+            let j = j.filter(|&j| keeps.0[i] && keeps.1[j]);
+            (node.clone(), j.map(|j| new[j].clone()))
+        })
+        .collect();
+    lists.push(PairedList {
+        range,
+        statements,
+        after,
+    });
+    if let Some(((old, new), lines)) = nested.filter(|((old, new), _)| old.len() == new.len()) {
+        for (old, new) in old.into_iter().zip(new) {
+            pair_lists(old, new, texts, Some(lines), lists);
+        }
+    }
+}
+
+fn is_list(node: &SyntaxNode) -> bool {
+    matches!(
+        node.kind(),
+        Rule::toplevel | Rule::block | Rule::function_body
+    )
+}
+
+/// Lists of statements within a statement, outside of the statements of these lists.
+fn nested_lists(node: SyntaxNode, lists: &mut Vec<SyntaxNode>) {
+    for child in node.children() {
+        match is_list(&child) {
+            true => lists.push(child),
+            false => nested_lists(child, lists),
+        }
+    }
+}
+
+/// Whether the comments within each statement of a list are attached to it, given the first line
+/// of the statement containing the list. Comments can also be attached to the statement containing
+/// the list when on its first line, and to a statement before on the same line that attaches the
+/// comments following it, without the tree of the statement holding them changing.
+fn keeps_comments(list: &[SyntaxNode], text: &str, first_line: Option<usize>) -> Vec<bool> {
+    let mut attached_line = first_line;
+    list.iter()
+        .map(|node| {
+            let span = node.span();
+            let keeps = attached_line != Some(span.start.line);
+            match attaches_following(node, text) {
+                true => {
+                    attached_line = Some(span.end.line);
+                    false
                 }
-            )),
+                false => keeps,
+            }
         })
+        .collect()
+}
+
+/// Whether comments following a statement on its last line can be attached within it, to the
+/// last statement of one of its blocks, which happens when that statement ends on the same line.
+fn attaches_following(node: &SyntaxNode, text: &str) -> bool {
+    let stmt = match node.kind() {
+        Rule::statement_semi => node.children().next(),
+        _ => Some(node.clone()),
+    };
+    let stmt = match stmt {
+        Some(stmt) => stmt,
+        None => return false,
+    };
+    let compound = matches!(
+        stmt.kind(),
+        Rule::if_block
+            | Rule::switch_block
+            | Rule::try_block
+            | Rule::unwind_protect
+            | Rule::function_def
+            | Rule::function_unterminated
+            | Rule::for_loop
+            | Rule::while_loop
+            | Rule::do_until
+    );
+    let closing = stmt.children().last().filter(|n| {
+        matches!(
+            n.kind(),
+            Rule::kw_endif
+                | Rule::kw_endfor
+                | Rule::kw_endwhile
+                | Rule::kw_endfunction
+                | Rule::kw_endswitch
+                | Rule::kw_end_try_catch
+                | Rule::kw_end_unwind_protect
+        )
+    });
+    // The last statement of a block ends on the line of the closing keyword when it's before the
+    // keyword on that line, or continued onto it, as the rest of a line after `...` is ignored
+    let alone = closing.is_some_and(|keyword| {
+        let start = keyword.range().start;
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let previous_line = text[..line_start].trim_end_matches(['\r', '\n']);
+        let previous_line = &previous_line[previous_line.rfind('\n').map_or(0, |i| i + 1)..];
+        text[line_start..start].trim().is_empty()
+            && !previous_line.contains("...")
+            && !previous_line.trim_end().ends_with('\\')
+    });
+    let rest = &text[node.range().end..];
+    compound && !alone && rest[..rest.find('\n').unwrap_or(rest.len())].contains(['%', '#'])
+}
+
+/// Statements of the list at the given byte range within a statement, taken out of it.
+fn take_list(stmt: &mut Statement, range: &Range<usize>) -> Vec<Node<Statement>> {
+    let children = match stmt {
+        Statement::Block(v) => v.iter_mut().map(|s| (s.range(), &mut s.data)).collect(),
+        stmt => bodies(stmt),
+    };
+    for (r, stmt) in children {
+        match stmt {
+            Statement::Block(v) if r == *range => return std::mem::take(v),
+            stmt if r.start <= range.start && range.end <= r.end => return take_list(stmt, range),
+            _ => {}
+        }
+    }
+    vec![]
+}
+
+/// Statements directly under a statement, with their byte ranges, which are its blocks except for
+/// the statement of `IgnoreOutput`.
+fn bodies(stmt: &mut Statement) -> Vec<(Range<usize>, &mut Statement)> {
+    fn boxed(b: &mut Node<Box<Statement>>) -> (Range<usize>, &mut Statement) {
+        (b.range(), &mut b.data)
+    }
+    match stmt {
+        Statement::IgnoreOutput(s) => vec![boxed(s)],
+        Statement::If(branches, otherwise) | Statement::Switch(_, branches, otherwise) => branches
+            .iter_mut()
+            .map(|(_, b)| (b.range(), &mut b.data))
+            .chain(otherwise.iter_mut().map(boxed))
+            .collect(),
+        Statement::Try(b, _, c) => std::iter::once(b).chain(c).map(boxed).collect(),
+        Statement::UnwindProtect(b, c) => vec![boxed(b), boxed(c)],
+        Statement::Function(_, _, _, b)
+        | Statement::For(_, _, b)
+        | Statement::While(_, b)
+        | Statement::DoUntil(b, _) => vec![boxed(b)],
+        _ => vec![],
+    }
+}
+
+/// Moves the positions of a reused node from where it started in the previous text to where it
+/// starts in the new one.
+struct Shift {
+    from: Position,
+    to: Position,
+}
+
+impl Shift {
+    fn shift(&self, pos: &mut Position) {
+        // Columns only change on the first line of the node
+        if pos.line == self.from.line {
+            pos.col = pos.col - self.from.col + self.to.col;
+        }
+        pos.line = pos.line - self.from.line + self.to.line;
+        pos.offset = pos.offset - self.from.offset + self.to.offset;
+    }
+}
+
+impl VisitorMut for Shift {
+    fn visit_name_mut(&mut self, node: &mut Node<String>) {
+        self.shift(&mut node.span.start);
+        self.shift(&mut node.span.end);
+    }
+
+    fn visit_span_mut(&mut self, span: &mut Range<Position>, comments: &mut [Comment]) {
+        for span in std::iter::once(span).chain(comments.iter_mut().map(|c| &mut c.span)) {
+            self.shift(&mut span.start);
+            self.shift(&mut span.end);
+        }
+    }
+}
+
+/// Tracks the variables bound by the statements of an AST as `ResolveIndexing` does, to find the
+/// variables bound before the statements starting at the given offsets.
+struct Bound {
+    vars: HashSet<String>,
+    /// Offsets left to reach, in decreasing order
+    until: Vec<usize>,
+    /// Variables bound before each offset reached
+    found: Vec<HashSet<String>>,
 }
 
-fn process_stmt(pair: Pair<Rule>) -> Node<Statement> {
-    match pair.as_rule() {
-        Rule::toplevel => Node {
-            span: to_range(pair.as_span()),
-            data: Statement::Block(pair.into_inner().map(process_stmt).collect()),
+impl<'a> Visitor<'a> for Bound {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        let start = node.span.start.offset;
+        while self.until.last().is_some_and(|&until| until <= start) {
+            self.until.pop();
+            self.found.push(self.vars.clone());
+        }
+        let until = match self.until.last() {
+            Some(&until) => until,
+            None => return,
+        };
+        match node.data {
+            Statement::Assignment(t, e) => match (&t.data, &e.data) {
+                (Expr::Identifier(name), Expr::AnonFunction(..))
+                | (Expr::Identifier(name), Expr::Handle(_)) => {
+                    self.vars.remove(name);
+                }
+                (t, _) => {
+                    if let Some(name) = t.root_name() {
+                        self.vars.insert(name.to_string());
+                    }
+                }
+            },
+            Statement::Try(b, err, c) => {
+                self.visit_stmt(b.as_deref());
+                if let Some(err) = err {
+                    self.vars.insert(err.data.clone());
+                }
+                if let Some(c) = c {
+                    self.visit_stmt(c.as_deref());
+                }
+            }
+            // Functions have their own scope
+            Statement::Function(outputs, _, inputs, b) if until < node.span.end.offset => {
+                let vars = outputs.iter().chain(inputs).map(|n| n.data.clone());
+                let outer = std::mem::replace(&mut self.vars, vars.collect());
+                self.visit_stmt(b.as_deref());
+                self.vars = outer;
+            }
+            Statement::Function(..) => {}
+            Statement::For(i, _, b) => {
+                self.vars.insert(i.clone());
+                self.visit_stmt(b.as_deref());
+            }
+            stmt => walk_stmt(self, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, _node: Node<&'a Expr>) {}
+}
+
+/// Binds the variables that a resolved statement binds.
+fn bind(stmt: &Node<Statement>, vars: &mut HashSet<String>) {
+    let mut bound = Bound {
+        vars: std::mem::take(vars),
+        until: vec![usize::MAX],
+        found: vec![],
+    };
+    bound.visit_stmt(stmt.as_ref());
+    *vars = bound.vars;
+}
+
+/// Finds whether a statement uses any of the given names as an expression, outside of the
+/// functions it defines.
+struct Mentions<'b> {
+    names: &'b HashSet<String>,
+    found: bool,
+}
+
+impl<'a> Visitor<'a> for Mentions<'_> {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        match node.data {
+            Statement::Function(..) => {}
+            stmt => walk_stmt(self, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, node: Node<&'a Expr>) {
+        match node.data {
+            Expr::Identifier(name) if self.names.contains(name) => self.found = true,
+            expr => walk_expr(self, expr),
+        }
+    }
+}
+
+fn error_message(e: pest::error::Error<Rule>) -> String {
+    format!(
+        "Parse error: {}",
+        match e.variant {
+            pest::error::ErrorVariant::CustomError { message } => message,
+            pest::error::ErrorVariant::ParsingError {
+                negatives,
+                positives,
+            } => format!("Unexpected {:?}, expected {:?}", negatives, positives),
+        }
+    )
+}
+
+/// Builds the node of a statement, taking the statements of its lists that are reused from
+/// `reuse` instead.
+fn process_stmt(node: SyntaxNode, reuse: &mut Reuse) -> Node<Statement> {
+    match node.kind() {
+        Rule::toplevel | Rule::block | Rule::function_body => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Statement::Block(
+                node.children()
+                    .map(|n| match reuse.take(&n) {
+                        Some(stmt) => stmt,
+                        None => process_stmt(n, reuse),
+                    })
+                    .collect(),
+            ),
         },
         Rule::assignment => Node {
-            span: to_range(pair.as_span()),
+            span: node.span(),
+            comments: vec![],
             data: {
-                let mut it = pair.into_inner();
-                let ident = it.next().unwrap().as_str().into();
+                let mut it = node.children();
+                let target = process_expr(it.next().unwrap());
                 let expr = it.next().map(process_expr).unwrap();
-                Statement::Assignment(ident, expr)
+                Statement::Assignment(target, expr)
             },
         },
         Rule::statement_semi => Node {
-            span: to_range(pair.as_span()),
+            span: node.span(),
+            comments: vec![],
             data: Statement::IgnoreOutput(
-                pair.into_inner()
-                    .map(process_stmt)
+                node.children()
+                    .map(|n| process_stmt(n, reuse))
                     .map(|n| n.map(Box::new))
                     .next()
                     .unwrap(),
             ),
         },
-        Rule::expr => Node {
-            span: to_range(pair.as_span()),
-            data: Statement::Expr({
-                let span = pair.as_span();
-                let mut it = pair.into_inner().map(process_expr).map(|n| n.map(Box::new));
-                let first = it.next().unwrap();
-                Node {
-                    span: to_range(span),
-                    data: {
-                        if let Some(second) = it.next() {
-                            if let Some(third) = it.next() {
-                                Expr::Range(first, Some(second), third)
-                            } else {
-                                Expr::Range(first, None, second)
-                            }
-                        } else {
-                            first.as_deref().map(Clone::clone).data
+        Rule::expr | Rule::command => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Statement::Expr(process_expr(node)),
+        },
+        Rule::if_block => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut branches = vec![];
+                let mut otherwise = None;
+                for node in node.children() {
+                    match node.kind() {
+                        Rule::if_clause | Rule::elseif_clause => {
+                            branches.push(process_branch(node, reuse))
+                        }
+                        Rule::else_clause => {
+                            otherwise = node
+                                .children()
+                                .nth(1)
+                                .map(|n| process_stmt(n, reuse))
+                                .map(|n| n.map(Box::new));
                         }
+                        _ => {}
                     }
                 }
-            })
+                Statement::If(branches, otherwise)
+            },
+        },
+        Rule::switch_block => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children().skip(1);
+                let subject = process_expr(it.next().unwrap());
+                let mut cases = vec![];
+                let mut otherwise = None;
+                for node in it {
+                    match node.kind() {
+                        Rule::case_clause => cases.push(process_branch(node, reuse)),
+                        Rule::otherwise_clause => {
+                            otherwise = node
+                                .children()
+                                .nth(1)
+                                .map(|n| process_stmt(n, reuse))
+                                .map(|n| n.map(Box::new));
+                        }
+                        _ => {}
+                    }
+                }
+                Statement::Switch(subject, cases, otherwise)
+            },
+        },
+        Rule::try_block => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children().skip(1);
+                let body = process_stmt(it.next().unwrap(), reuse).map(Box::new);
+                let mut err = None;
+                let mut catch = None;
+                if let Some(clause) = it.next().filter(|p| p.kind() == Rule::catch_clause) {
+                    for node in clause.children().skip(1) {
+                        match node.kind() {
+                            Rule::identifier => err = Some(process_name(node)),
+                            _ => catch = Some(process_stmt(node, reuse).map(Box::new)),
+                        }
+                    }
+                }
+                Statement::Try(body, err, catch)
+            },
+        },
+        Rule::unwind_protect => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children();
+                let body = process_stmt(it.nth(1).unwrap(), reuse).map(Box::new);
+                let cleanup = process_stmt(it.nth(1).unwrap(), reuse).map(Box::new);
+                Statement::UnwindProtect(body, cleanup)
+            },
+        },
+        Rule::function_def | Rule::function_unterminated => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut outputs = vec![];
+                let mut name = None;
+                let mut inputs = vec![];
+                let mut body = None;
+                for node in node.children() {
+                    match node.kind() {
+                        Rule::function_outputs => {
+                            outputs = node.children().map(process_name).collect()
+                        }
+                        Rule::identifier => name = Some(process_name(node)),
+                        Rule::function_params => {
+                            inputs = node.children().map(process_name).collect()
+                        }
+                        Rule::block | Rule::function_body => {
+                            body = Some(process_stmt(node, reuse).map(Box::new))
+                        }
+                        _ => {}
+                    }
+                }
+                Statement::Function(outputs, name.unwrap(), inputs, body.unwrap())
+            },
+        },
+        Rule::for_loop => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children().skip(1);
+                let ident = it.next().unwrap().text().into();
+                let expr = process_expr(it.next().unwrap());
+                let body = process_stmt(it.next().unwrap(), reuse).map(Box::new);
+                Statement::For(ident, expr, body)
+            },
+        },
+        Rule::while_loop => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children().skip(1);
+                let cond = process_expr(it.next().unwrap());
+                let body = process_stmt(it.next().unwrap(), reuse).map(Box::new);
+                Statement::While(cond, body)
+            },
+        },
+        Rule::do_until => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children().skip(1);
+                let body = process_stmt(it.next().unwrap(), reuse).map(Box::new);
+                let cond = process_expr(it.nth(1).unwrap());
+                Statement::DoUntil(body, cond)
+            },
         },
-        Rule::range_operand => Node {
-            span: to_range(pair.as_span()),
-            data: Statement::Expr(process_expr(pair.into_inner().next().unwrap()))
+        Rule::break_stmt => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Statement::Break,
+        },
+        Rule::continue_stmt => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Statement::Continue,
+        },
+        Rule::error_stmt => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Statement::Error(
+                OctaveParser::parse(Rule::single_statement, node.text())
+                    .err()
+                    .map(error_message)
+                    .unwrap_or_else(|| format!("Parse error, unexpected `{}`", node.text())),
+            ),
+        },
+        Rule::error_keyword => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Statement::Error(format!("Parse error, unexpected `{}`", node.text())),
         },
         Rule::EOI => Node {
-            span: to_range(pair.as_span()),
+            span: node.span(),
+            comments: vec![],
             data: Statement::EOI,
         },
         r => Node {
-            span: to_range(pair.as_span()),
+            span: node.span(),
+            comments: vec![],
             data: Statement::Error(format!("Parse error, unexpected {:?}", r)),
         },
     }
 }
 
-fn process_expr(pair: Pair<Rule>) -> Node<Expr> {
-    lazy_static! {
-        static ref PREC: PrecClimber<Rule> = PrecClimber::new(vec![
-            Operator::new(Rule::add, Assoc::Left) | Operator::new(Rule::sub, Assoc::Left),
-            Operator::new(Rule::mul, Assoc::Left) | Operator::new(Rule::div, Assoc::Left),
-            Operator::new(Rule::pow, Assoc::Right),
-            Operator::new(Rule::access, Assoc::Right),
-        ]);
+fn process_name(node: SyntaxNode) -> Node<String> {
+    Node {
+        span: node.span(),
+        comments: vec![],
+        data: node.text().into(),
     }
-    match pair.as_rule() {
-        Rule::expr => process_expr(pair.into_inner().next().unwrap()),
+}
+
+fn process_branch(node: SyntaxNode, reuse: &mut Reuse) -> (Node<Expr>, Node<Statement>) {
+    let mut it = node.children().skip(1);
+    let cond = process_expr(it.next().unwrap());
+    let body = process_stmt(it.next().unwrap(), reuse);
+    (cond, body)
+}
+
+fn process_expr(node: SyntaxNode) -> Node<Expr> {
+    match node.kind() {
+        Rule::range | Rule::matrix_range => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let mut it = node.children().map(process_expr).map(|n| n.map(Box::new));
+                let first = it.next().unwrap();
+                if let Some(second) = it.next() {
+                    if let Some(third) = it.next() {
+                        Expr::Range(first, Some(second), third)
+                    } else {
+                        Expr::Range(first, None, second)
+                    }
+                } else {
+                    *first.data
+                }
+            },
+        },
         Rule::single_value => Node {
-            span: to_range(pair.as_span()),
-            data: pair
-                .into_inner()
+            span: node.span(),
+            comments: vec![],
+            data: node
+                .children()
                 .map(process_expr)
                 .map(|v| Expr::Matrix(Matrix::from_vecs(vec![vec![v]])))
                 .next()
                 .unwrap_or(Expr::Error("Syntax error".into())),
         },
-        Rule::binary => PREC.climb(
-            pair.into_inner(),
-            process_expr,
-            |lhs: Node<Expr>, op: Pair<Rule>, rhs: Node<Expr>| Node {
-                span: union(lhs.span.clone(), rhs.span.clone()),
-                data: match get_op(op.as_rule()) {
-                    Ok(op) => Expr::Op(op, lhs.map(Box::new), rhs.map(Box::new)),
-                    Err(rule) => Expr::Error(format!("Unexpected {:?}", rule)),
+        Rule::expr | Rule::matrix_expr | Rule::arith | Rule::matrix_arith => {
+            let mut it = node.children().peekable();
+            let first = process_expr(it.next().unwrap());
+            climb(first, &mut it, 0)
+        }
+        Rule::unary | Rule::prefixed | Rule::matrix_unary | Rule::matrix_prefixed => {
+            let mut nodes = node.children().collect::<Vec<_>>();
+            let operand = process_expr(nodes.pop().unwrap());
+            nodes.into_iter().rev().fold(operand, |operand, op| Node {
+                span: op.span().start..operand.span.end,
+                comments: vec![],
+                data: match (get_unary_op(&op), operand.data) {
+                    // Negative number literals stay literals
+                    (Ok(UnaryOp::Neg), Expr::LitNumber(n)) => Expr::LitNumber(n.negate()),
+                    (Ok(UnaryOp::Neg), Expr::LitComplex(n)) => Expr::LitComplex(n.negate()),
+                    (Ok(UnaryOp::Neg), Expr::Matrix(m)) if is_number_literal(&m) => {
+                        Expr::Matrix(m.map(|n| n.map(|e| match e {
+                            Expr::LitNumber(n) => Expr::LitNumber(n.negate()),
+                            Expr::LitComplex(n) => Expr::LitComplex(n.negate()),
+                            e => e,
+                        })))
+                    }
+                    (Ok(op), data) => Expr::Unary(
+                        op,
+                        Node {
+                            span: operand.span,
+                            comments: operand.comments,
+                            data: Box::new(data),
+                        },
+                    ),
+                    (Err(rule), _) => Expr::Error(format!("Unexpected {:?}", rule)),
                 },
-            },
-        ),
-        Rule::call => Node {
-            span: to_range(pair.as_span()),
+            })
+        }
+        Rule::power | Rule::matrix_power => {
+            let mut it = node.children();
+            let first = process_expr(it.next().unwrap());
+            let mut result = first;
+            while let (Some(op), Some(rhs)) = (it.next(), it.next()) {
+                let rhs = process_expr(rhs);
+                result = Node {
+                    span: union(result.span.clone(), rhs.span.clone()),
+                    comments: vec![],
+                    data: match get_op(&op) {
+                        Ok(op) => Expr::Op(op, result.map(Box::new), rhs.map(Box::new)),
+                        Err(rule) => Expr::Error(format!("Unexpected {:?}", rule)),
+                    },
+                };
+            }
+            result
+        }
+        Rule::postfix | Rule::matrix_postfix | Rule::lvalue => {
+            // Assignment targets are always indexed, other expressions may be function calls
+            let is_target = node.kind() == Rule::lvalue;
+            let mut it = node.children();
+            let operand = process_expr(it.next().unwrap());
+            it.fold(operand, |operand, op| Node {
+                span: operand.span.start..op.span().end,
+                comments: vec![],
+                data: match op.kind() {
+                    Rule::index_args => {
+                        let args = op.children().map(process_expr).collect::<Vec<_>>();
+                        let is_index = is_target
+                            || !matches!(operand.data, Expr::Identifier(_))
+                            || args.iter().any(|a| matches!(a.data, Expr::Colon | Expr::End));
+                        if is_index {
+                            Expr::Index(operand.map(Box::new), args)
+                        } else {
+                            Expr::Call(operand.map(Box::new), args)
+                        }
+                    }
+                    Rule::cell_args => Expr::CellIndex(
+                        operand.map(Box::new),
+                        op.children().map(process_expr).collect(),
+                    ),
+                    Rule::incr => Expr::Incr(operand.map(Box::new)),
+                    Rule::decr => Expr::Decr(operand.map(Box::new)),
+                    Rule::field => Expr::Op(
+                        Op::Access,
+                        operand.map(Box::new),
+                        process_expr(op.children().next().unwrap()).map(Box::new),
+                    ),
+                    Rule::dynamic_field => Expr::Op(
+                        Op::DynamicAccess,
+                        operand.map(Box::new),
+                        process_expr(op.children().next().unwrap()).map(Box::new),
+                    ),
+                    _ => match get_unary_op(&op) {
+                        Ok(op) => Expr::Unary(op, operand.map(Box::new)),
+                        Err(rule) => Expr::Error(format!("Unexpected {:?}", rule)),
+                    },
+                },
+            })
+        }
+        Rule::operand => process_expr(node.children().next().unwrap()),
+        Rule::anon_function => Node {
+            span: node.span(),
+            comments: vec![],
             data: {
-                let mut it = pair.into_inner().map(process_expr);
-                let ident = it.next().unwrap();
-                let exprs = it.collect();
-                Expr::Call(ident.map(Box::new), exprs)
+                let mut it = node.children();
+                let params = it.next().unwrap().children().map(process_name).collect();
+                let body = process_expr(it.next().unwrap());
+                Expr::AnonFunction(params, body.map(Box::new))
             },
         },
-        Rule::matrix => Node {
-            span: to_range(pair.as_span()),
+        Rule::handle => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Expr::Handle(process_name(node.children().next().unwrap())),
+        },
+        Rule::colon_all => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Expr::Colon,
+        },
+        Rule::end_index => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Expr::End,
+        },
+        Rule::paren => process_expr(node.children().next().unwrap()),
+        Rule::matrix | Rule::cell => Node {
+            span: node.span(),
+            comments: vec![],
             data: {
-                let data: Vec<Vec<_>> = pair
-                    .into_inner()
-                    .map(|line| line.into_inner().map(process_expr).collect())
+                let is_cell = node.kind() == Rule::cell;
+                let data: Vec<Vec<_>> = node
+                    .children()
+                    .map(|line| line.children().map(process_expr).collect())
                     .collect();
-                let innerlen = data[0].len();
+                let innerlen = data.first().map_or(0, Vec::len);
                 if !data.iter().all(|v| v.len() == innerlen) {
-                    let sizes = data.iter().map(|v| v.len()).collect::<HashSet<_>>();
+                    let sizes = data.iter().map(|v| v.len()).collect::<BTreeSet<_>>();
                     Expr::Error(format!(
-                        "Matrix sizing error: found lines of sizes {:?}",
+                        "{} sizing error: found lines of sizes {:?}",
+                        if is_cell { "Cell array" } else { "Matrix" },
                         sizes
                     ))
+                } else if is_cell {
+                    Expr::Cell(Matrix::from_vecs(data))
                 } else {
                     Expr::Matrix(Matrix::from_vecs(data))
                 }
             },
         },
         Rule::identifier => Node {
-            span: to_range(pair.as_span()),
-            data: Expr::Identifier(pair.as_str().to_string()),
+            span: node.span(),
+            comments: vec![],
+            data: Expr::Identifier(node.text().to_string()),
         },
         Rule::string => Node {
-            span: to_range(pair.as_span()),
-            data: Expr::LitString(
-                pair.into_inner()
-                    .map(|x| match x.as_str() {
-                        "\\\"" => "\"".into(),
-                        x => x.to_string(),
+            span: node.span(),
+            comments: vec![],
+            data: Expr::LitString(StringLiteral {
+                raw: node.text().to_string(),
+                value: node.children().map(unescape_char).collect(),
+            }),
+        },
+        Rule::command => {
+            let span = node.span();
+            let mut it = node.children();
+            let name = process_expr(it.next().unwrap()).map(Box::new);
+            Node {
+                span,
+                comments: vec![],
+                data: Expr::Command(name, it.map(process_expr).collect()),
+            }
+        }
+        Rule::command_word => Node {
+            span: node.span(),
+            comments: vec![],
+            data: Expr::LitString(StringLiteral {
+                value: node.text().to_string(),
+                raw: node.text().to_string(),
+            }),
+        },
+        Rule::decimal => Node {
+            span: node.span(),
+            comments: vec![],
+            data: parse_decimal(node.text())
+                .map(|value| {
+                    Expr::LitNumber(NumberLiteral {
+                        value,
+                        raw: node.text().to_string(),
                     })
-                    .flat_map(|x| x.chars().collect::<Vec<_>>().into_iter())
-                    .collect(),
-            ),
+                })
+                .unwrap_or(Expr::Error("Cannot parse number".into())),
         },
-        Rule::number => Node {
-            span: to_range(pair.as_span()),
-            data: pair
-                .as_str()
-                .parse()
-                .map(Expr::LitNumber)
+        Rule::imaginary => Node {
+            span: node.span(),
+            comments: vec![],
+            data: parse_decimal(&node.text()[..node.text().len() - 1])
+                .map(|value| {
+                    Expr::LitComplex(NumberLiteral {
+                        value,
+                        raw: node.text().to_string(),
+                    })
+                })
                 .unwrap_or(Expr::Error("Cannot parse number".into())),
         },
+        Rule::hex_number | Rule::binary_number => Node {
+            span: node.span(),
+            comments: vec![],
+            data: {
+                let radix = if node.kind() == Rule::hex_number { 16 } else { 2 };
+                parse_integer(node.text(), radix).unwrap_or_else(Expr::Error)
+            },
+        },
         _ => Node {
-            span: to_range(pair.as_span()),
-            data: Expr::Error(format!("Syntax error, unexpected {:?}", pair.as_rule())),
+            span: node.span(),
+            comments: vec![],
+            data: Expr::Error(format!("Syntax error, unexpected {:?}", node.kind())),
         },
     }
 }
@@ -192,35 +1236,177 @@ fn union<T: Ord>(p0: Range<T>, p1: Range<T>) -> Range<T> {
     min(p0.start, p1.start)..max(p0.end, p1.end)
 }
 
-fn get_op(rule: Rule) -> Result<Op, Rule> {
-    Ok(match rule {
+/// Folds a sequence of operands separated by binary operators, starting with `lhs`, into a tree
+/// following operator precedence. All operators are left-associative.
+fn climb<I: Iterator<Item = SyntaxNode>>(
+    lhs: Node<Expr>,
+    it: &mut Peekable<I>,
+    min_precedence: u8,
+) -> Node<Expr> {
+    let mut lhs = lhs;
+    let next_precedence = |it: &mut Peekable<I>| it.peek().map(precedence);
+    while let Some(current) = next_precedence(it).filter(|&p| p >= min_precedence) {
+        let op = it.next().unwrap();
+        let mut rhs = match it.next() {
+            Some(rhs) => process_expr(rhs),
+            None => break,
+        };
+        while let Some(next) = next_precedence(it).filter(|&p| p > current) {
+            rhs = climb(rhs, it, next);
+        }
+        lhs = Node {
+            span: union(lhs.span.clone(), rhs.span.clone()),
+            comments: vec![],
+            data: match get_op(&op) {
+                Ok(op) => Expr::Op(op, lhs.map(Box::new), rhs.map(Box::new)),
+                Err(rule) => Expr::Error(format!("Unexpected {:?}", rule)),
+            },
+        };
+    }
+    lhs
+}
+
+/// Binding strength of binary operators handled by `climb`, from loosest to tightest.
+fn precedence(op: &SyntaxNode) -> u8 {
+    get_op(op).map_or(7, |op| op.precedence())
+}
+
+fn get_op(op: &SyntaxNode) -> Result<Op, Rule> {
+    Ok(match op.kind() {
         Rule::add => Op::Add,
         Rule::sub => Op::Sub,
         Rule::mul => Op::Mul,
         Rule::div => Op::Div,
+        Rule::ldiv => Op::LeftDiv,
         Rule::pow => Op::Pow,
-        Rule::access => Op::Access,
-        _ => return Err(rule),
+        Rule::emul => Op::ElemMul,
+        Rule::ediv => Op::ElemDiv,
+        Rule::eldiv => Op::ElemLeftDiv,
+        Rule::epow => Op::ElemPow,
+        Rule::eq => Op::Eq,
+        Rule::ne => Op::Ne(negation(op)),
+        Rule::lt => Op::Lt,
+        Rule::le => Op::Le,
+        Rule::gt => Op::Gt,
+        Rule::ge => Op::Ge,
+        Rule::and => Op::And,
+        Rule::or => Op::Or,
+        Rule::andand => Op::ShortAnd,
+        Rule::oror => Op::ShortOr,
+        rule => return Err(rule),
     })
 }
 
-fn to_range(span: Span) -> Range<Position> {
-    to_pos(span.start_pos())..to_pos(span.end_pos())
+fn get_unary_op(op: &SyntaxNode) -> Result<UnaryOp, Rule> {
+    Ok(match op.kind() {
+        Rule::neg => UnaryOp::Neg,
+        Rule::plus => UnaryOp::Plus,
+        Rule::not => UnaryOp::Not(negation(op)),
+        Rule::pre_incr => UnaryOp::PreIncr,
+        Rule::pre_decr => UnaryOp::PreDecr,
+        Rule::transpose => UnaryOp::Transpose,
+        Rule::ctranspose => UnaryOp::CTranspose,
+        rule => return Err(rule),
+    })
+}
+
+fn negation(op: &SyntaxNode) -> Negation {
+    if op.text().starts_with('~') {
+        Negation::Tilde
+    } else {
+        Negation::Bang
+    }
+}
+
+/// Whether the matrix is a single number, which is how number literals come out of `single_value`.
+fn is_number_literal(m: &Matrix<Node<Expr>>) -> bool {
+    m.len() == 1 && matches!(m.data[0].data, Expr::LitNumber(_) | Expr::LitComplex(_))
 }
 
-fn to_pos(p: pest::Position) -> Position {
-    let (line, col) = p.line_col();
-    Position { line, col }
+/// Value of a single `char_simple` or `char_double`, which may be an escape sequence. Unknown
+/// escape sequences stand for the escaped character itself, as they do in Octave.
+fn unescape_char(node: SyntaxNode) -> String {
+    let escaped = match (node.kind(), node.text()) {
+        (_, "''") => return "'".into(),
+        (_, "\"\"") => return "\"".into(),
+        (Rule::char_double, text) if text.len() > 1 && text.starts_with('\\') => &text[1..],
+        (_, text) => return text.to_string(),
+    };
+    let code = if let Some(hex) = escaped.strip_prefix('x').filter(|h| !h.is_empty()) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if escaped.starts_with(|c: char| c.is_digit(8)) {
+        u32::from_str_radix(escaped, 8).ok()
+    } else {
+        None
+    };
+    if let Some(c) = code.and_then(std::char::from_u32) {
+        return c.to_string();
+    }
+    match escaped {
+        "n" => "\n",
+        "t" => "\t",
+        "r" => "\r",
+        "a" => "\x07",
+        "b" => "\x08",
+        "f" => "\x0C",
+        "v" => "\x0B",
+        // Line continuation
+        "\n" | "\r\n" => "",
+        escaped => escaped,
+    }
+    .to_string()
+}
+
+/// Parses a decimal literal, which may use `d` as its exponent marker and underscores as digit
+/// separators.
+fn parse_decimal(text: &str) -> Option<f64> {
+    text.replace('_', "").replace(['d', 'D'], "e").parse().ok()
+}
+
+/// Parses a hexadecimal or binary literal including its `0x`/`0b` prefix and optional type suffix.
+/// Without suffix, the literal is the smallest unsigned integer type holding all of its digits.
+fn parse_integer(text: &str, radix: u32) -> Result<Expr, String> {
+    let (digits, suffix) = match text[2..].find(['u', 's']) {
+        Some(i) => (&text[2..i + 2], Some(&text[i + 2..])),
+        None => (&text[2..], None),
+    };
+    let digits = digits.replace('_', "");
+    let ty = match suffix {
+        Some(s) => IntegerType::new(s.starts_with('s'), s[1..].parse().unwrap()),
+        None => {
+            let bits = digits.len() as u32 * if radix == 16 { 4 } else { 1 };
+            let bits = [8, 16, 32, 64].iter().copied().find(|&b| bits <= b).unwrap_or(64);
+            IntegerType::new(false, bits)
+        }
+    }
+    .unwrap();
+    let max = match (ty.is_signed(), ty.bits()) {
+        (false, 64) => u64::MAX,
+        (false, bits) => (1 << bits) - 1,
+        (true, bits) => (1 << (bits - 1)) - 1,
+    };
+    match u64::from_str_radix(&digits, radix) {
+        Ok(value) if value <= max => Ok(Expr::LitInteger(IntegerLiteral {
+            value,
+            ty,
+            raw: text.to_string(),
+        })),
+        _ => Err(format!("Integer literal out of range for {}", ty)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse;
+    use super::{from_cst, from_edit, parse};
     use crate::{
-        ast::{Expr, Statement},
-        node::{Node, Position},
+        ast::{Expr, Op, Statement, UnaryOp},
+        cst::SyntaxNode,
+        node::{range_to_lsp, CommentKind, Node, Position},
+        sexp::stmt_to_sexp,
         value::Matrix,
     };
+    use flurry::HashMap;
+    use octave_typesystem::{CallableType, CellType, IntegerType, SimpleType, Type};
     use std::ops::Deref;
 
     #[test]
@@ -314,15 +1500,9 @@ mod tests {
     #[test]
     fn stmt_block() {
         let actual = parse("[1 2 3]\n[4 5 6];");
-        let actual_vals = if let Statement::Block(v) = actual.deref() {
+        let _actual_vals = if let Statement::Block(v) = actual.deref() {
             v.iter()
-                .take_while(|n| {
-                    if let Statement::EOI = Node::deref(*n) {
-                        false
-                    } else {
-                        true
-                    }
-                })
+                .take_while(|n| !matches!(Node::deref(*n), Statement::EOI))
                 .map(|v| v.get_matrix().unwrap())
                 .collect()
         } else {
@@ -332,4 +1512,882 @@ mod tests {
         println!("Errors: {:?}", actual.as_ref().get_errors());
         assert_eq!(0, actual.as_ref().get_errors().len());
     }
+
+    #[test]
+    fn if_block() {
+        let actual = parse("if x\n  y = 1;\nelseif z\n  y = 2;\nelse\n  y = 3;\nend");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            if let Statement::If(branches, otherwise) = v[0].deref() {
+                assert_eq!(2, branches.len());
+                assert!(otherwise.is_some());
+            } else {
+                unreachable!()
+            }
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn if_endif() {
+        let actual = parse("if (a + 1), b = a; endif");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+    }
+
+    #[test]
+    fn loops() {
+        let actual = parse(
+            "for i = 1:10\n  if i, continue; end\nendfor\nwhile x\n  break\nend\ndo\n  x = x - 1;\nuntil x",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            assert!(matches!(v[0].deref(), Statement::For(..)));
+            assert!(matches!(v[1].deref(), Statement::While(..)));
+            assert!(matches!(v[2].deref(), Statement::DoUntil(..)));
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn for_bindings() {
+        let actual = parse("m = [1 2 3; 4 5 6];\nfor (c = m) end\nfor i = 1:3 end");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((2, 1)),
+                ty: SimpleType::Double
+            }),
+            ctx.get("c")
+        );
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 1)),
+                ty: SimpleType::Double
+            }),
+            ctx.get("i")
+        );
+    }
+
+    #[test]
+    fn function() {
+        let actual = parse(
+            "function [a, b] = f(x, y, varargin)\n  a = x;\n  function g()\n  end\n  b = [1 2];\nendfunction",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            if let Statement::Function(outputs, name, inputs, _) = v[0].deref() {
+                assert_eq!(2, outputs.len());
+                assert_eq!("f", name.as_str());
+                assert_eq!(3, inputs.len());
+            } else {
+                unreachable!()
+            }
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn subfunctions() {
+        let actual = parse("function r = f(x)\n  r = g(x);\nfunction r = g(x)\n  r = [x x];\n");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        assert_eq!(
+            Some(&Type::Callable(CallableType {
+                args_types: vec![Type::Unknown],
+                return_type: Box::new(Type::Matrix {
+                    size: None,
+                    ty: SimpleType::Unknown
+                }),
+            })),
+            ctx.get("g")
+        );
+        assert!(ctx.get("r").is_none());
+    }
+
+    #[test]
+    fn comments() {
+        let actual = parse(
+            "% leading\nx = 1; # trailing\n%{\nblock\n%}\ny = 'not % a comment';\nif x % condition\n  z = x;\nend",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            let x = v[0].comments();
+            assert_eq!(2, x.len());
+            assert_eq!(" leading", x[0].content());
+            assert_eq!(" trailing", x[1].content());
+            assert_eq!(1, v[0].leading_comments().count());
+            assert_eq!(1, v[0].trailing_comments().count());
+            let y = v[1].comments();
+            assert_eq!(1, y.len());
+            assert_eq!(CommentKind::Block, y[0].kind);
+            assert_eq!("block", y[0].content());
+            assert_eq!(" condition", v[2].comments()[0].content());
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn block_comments() {
+        fn texts<T>(node: &Node<T>) -> Vec<&str> {
+            node.comments().iter().map(|c| c.text.as_str()).collect()
+        }
+        let actual = parse(
+            "function f()\n  % TODO implement\nend\n\
+             if c\n  x = 1;\n  % before else\nelse\n  % only comment\nend\n\
+             for i = 1:3\n  x = i;\n  % after\nend\ny = 2;",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let v = match actual.deref() {
+            Statement::Block(v) => v,
+            _ => unreachable!(),
+        };
+        match v[0].deref() {
+            Statement::Function(_, _, _, b) => assert_eq!(vec!["% TODO implement"], texts(b)),
+            _ => unreachable!(),
+        }
+        match v[1].deref() {
+            Statement::If(branches, Some(otherwise)) => {
+                assert_eq!(vec!["% before else"], texts(&branches[0].1));
+                assert_eq!(vec!["% only comment"], texts(otherwise));
+            }
+            _ => unreachable!(),
+        }
+        match v[2].deref() {
+            Statement::For(_, _, b) => assert_eq!(vec!["% after"], texts(b)),
+            _ => unreachable!(),
+        }
+        assert!(v[3].comments().is_empty());
+    }
+
+    #[test]
+    fn error_recovery() {
+        let actual = parse("x = 1;\ny = (2 +;\nif x\n  z = *;\nend\nw = 3\nend");
+        println!("{:#?}", actual);
+        let errors = actual.as_ref().get_errors();
+        println!("{:#?}", errors);
+        assert_eq!(
+            vec![2, 4, 7],
+            errors.iter().map(|e| e.span.start.line).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Position {
+                offset: 7,
+                line: 2,
+                col: 1
+            }..Position {
+                offset: 16,
+                line: 2,
+                col: 10
+            },
+            errors[0].span
+        );
+        if let Statement::Block(v) = actual.deref() {
+            assert!(matches!(v[0].deref(), Statement::IgnoreOutput(..)));
+            assert!(matches!(v[2].deref(), Statement::If(..)));
+            assert!(matches!(v[3].deref(), Statement::Assignment(..)));
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn matrix_rows() {
+        let actual = parse("x = [];\ny = [1 2\n     3 4];");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((2, 2)),
+                ty: SimpleType::Double
+            }),
+            ctx.pin().get("y")
+        );
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let actual = parse("a + b == c & d");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let expr = if let Statement::Block(v) = actual.deref() {
+            match v[0].deref() {
+                Statement::Expr(e) => e.clone(),
+                _ => unreachable!(),
+            }
+        } else {
+            unreachable!()
+        };
+        if let Expr::Op(Op::And, lhs, _) = expr.deref() {
+            assert!(matches!(lhs.deref().deref(), Expr::Op(Op::Eq, ..)));
+        } else {
+            panic!("Expected `&` at the root, got {:?}", expr);
+        }
+    }
+
+    #[test]
+    fn comparison_type() {
+        let actual = parse("x = [1 2 3] < 2;\ny = 1 == 1 && 2 ~= 3;");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 3)),
+                ty: SimpleType::Logical
+            }),
+            ctx.pin().get("x")
+        );
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 1)),
+                ty: SimpleType::Logical
+            }),
+            ctx.pin().get("y")
+        );
+    }
+
+    #[test]
+    fn matrix_whitespace() {
+        for (input, width) in &[("[1 -2]", 2), ("[1 - 2]", 1), ("[1-2]", 1), ("[a .* b]", 1)] {
+            let actual = parse(input);
+            println!("{:#?}", actual);
+            assert_eq!(0, actual.as_ref().get_errors().len());
+            let width = *width;
+            assert!(matches!(
+                actual.deref(),
+                Statement::Block(v) if matches!(v[0].deref(), Statement::Expr(e) if matches!(
+                    e.deref(), Expr::Matrix(m) if m.width() == width
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn unary() {
+        let actual = parse("x = -2^2;\ny = !a';\nx++;\n--x;");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let stmts = if let Statement::Block(v) = actual.deref() {
+            v.iter()
+                .map(|n| match n.deref() {
+                    Statement::IgnoreOutput(s) => s.deref().deref().clone(),
+                    s => s.clone(),
+                })
+                .collect::<Vec<_>>()
+        } else {
+            unreachable!()
+        };
+        assert!(matches!(
+            &stmts[0],
+            Statement::Assignment(_, e) if matches!(e.deref(), Expr::Unary(UnaryOp::Neg, e) if matches!(
+                e.deref().deref(), Expr::Op(Op::Pow, ..)
+            ))
+        ));
+        assert!(matches!(
+            &stmts[1],
+            Statement::Assignment(_, e) if matches!(e.deref(), Expr::Unary(UnaryOp::Not(_), e) if matches!(
+                e.deref().deref(), Expr::Unary(UnaryOp::CTranspose, ..)
+            ))
+        ));
+        assert!(matches!(&stmts[2], Statement::Expr(e) if matches!(e.deref(), Expr::Incr(_))));
+        assert!(matches!(
+            &stmts[3],
+            Statement::Expr(e) if matches!(e.deref(), Expr::Unary(UnaryOp::PreDecr, _))
+        ));
+    }
+
+    #[test]
+    fn transpose() {
+        let actual = parse("x = [1 2 3]';\ny = [x' 'abc'];");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((3, 1)),
+                ty: SimpleType::Double
+            }),
+            ctx.pin().get("x")
+        );
+        assert!(matches!(
+            ctx.pin().get("y"),
+            Some(Type::Matrix {
+                ty: SimpleType::String,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn indexing() {
+        let actual = parse("a = [1 2 3; 4 5 6];\nb = a(end, :);\nc = a(a > 2);\nd = f(1);");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            let rhs = |n: &Node<Statement>| match n.deref() {
+                Statement::IgnoreOutput(s) => match s.deref().deref() {
+                    Statement::Assignment(_, e) => e.deref().clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+            assert!(matches!(rhs(&v[1]), Expr::Index(_, args) if matches!(
+                (args[0].deref(), args[1].deref()),
+                (Expr::End, Expr::Colon)
+            )));
+            assert!(matches!(rhs(&v[2]), Expr::Index(..)));
+            assert!(matches!(rhs(&v[3]), Expr::Call(..)));
+        }
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 3)),
+                ty: SimpleType::Double
+            }),
+            ctx.pin().get("b")
+        );
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: None,
+                ty: SimpleType::Double
+            }),
+            ctx.pin().get("c")
+        );
+    }
+
+    #[test]
+    fn indexing_in_matrices() {
+        for (input, expected) in &[
+            (
+                "z = [x(1) y(2)]",
+                "(= z (matrix (row (call x 1) (call y 2))))",
+            ),
+            ("[x{1} y]", "(matrix (row (cell-index x 1) y))"),
+            ("[f(1) -1]", "(matrix (row (call f 1) -1))"),
+            ("[a(1) (2)]", "(matrix (row (call a 1) 2))"),
+        ] {
+            let actual = parse(input);
+            assert_eq!(0, actual.as_ref().get_errors().len(), "{}", input);
+            let sexp = stmt_to_sexp(&actual);
+            assert!(sexp.contains(expected), "{}", sexp);
+        }
+    }
+
+    #[test]
+    fn assignment_targets() {
+        let actual = parse("a(2, :) = [1 2 3];\ns.f = 4;\nc{2} = 'x';");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            let targets = v
+                .iter()
+                .filter_map(|n| match n.deref() {
+                    Statement::IgnoreOutput(s) => match s.deref().deref() {
+                        Statement::Assignment(t, _) => Some(t.deref().clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert!(matches!(&targets[0], Expr::Index(_, args) if args.len() == 2));
+            assert!(matches!(&targets[1], Expr::Op(Op::Access, ..)));
+            assert!(matches!(&targets[2], Expr::CellIndex(..)));
+            assert_eq!(
+                vec![Some("a"), Some("s"), Some("c")],
+                targets.iter().map(Expr::root_name).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn cell_arrays() {
+        let actual = parse(
+            "c = {1, 'b'; [1 2], {}};\nx = c{2, 1};\ny = c{3};\nz = c(1, :);\nw = [c{:}];",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        assert!(matches!(
+            ctx.get("c"),
+            Some(Type::Cell(CellType { size: Some((2, 2)), elements: Some(e) })) if e.len() == 4
+        ));
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 2)),
+                ty: SimpleType::Double
+            }),
+            ctx.get("x")
+        );
+        assert_eq!(Some(&Type::SimpleType(SimpleType::String)), ctx.get("y"));
+        assert!(matches!(
+            ctx.get("z"),
+            Some(Type::Cell(CellType { size: Some((1, 2)), .. }))
+        ));
+        assert!(matches!(ctx.get("w"), Some(Type::Matrix { size: None, .. })));
+        let actual = parse("{c{1} 2}");
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let sexp = stmt_to_sexp(&actual);
+        assert!(sexp.contains("(cell (row (cell-index c 1) 2))"), "{}", sexp);
+    }
+
+    #[test]
+    fn struct_fields() {
+        let actual =
+            parse("s.x = 1;\ns.y = 'a';\ns.z.w = [1 2];\na = s.z.w;\nb = s.('y');\nc = s.(b);");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        let s = match ctx.get("s") {
+            Some(Type::Struct(s)) => s,
+            ty => panic!("Expected a struct, got {:?}", ty),
+        };
+        assert_eq!(
+            vec!["x", "y", "z"],
+            s.fields.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>()
+        );
+        let string = Type::Matrix {
+            size: None,
+            ty: SimpleType::String,
+        };
+        assert_eq!(Some(&string), s.field("y"));
+        assert!(matches!(s.field("z"), Some(Type::Struct(z)) if z.field("w").is_some()));
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 2)),
+                ty: SimpleType::Double
+            }),
+            ctx.get("a")
+        );
+        assert_eq!(Some(&string), ctx.get("b"));
+        assert_eq!(Some(&Type::Unknown), ctx.get("c"));
+    }
+
+    #[test]
+    fn anonymous_functions() {
+        let actual = parse(
+            "a = [1 2];\nf = @(x) x.^2 + a;\ny = f(3);\n\
+             n = cellfun(@numel, {1, 'ab'});\nh = @() a * 2;",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            assert!(matches!(
+                v[2].deref(),
+                Statement::IgnoreOutput(s) if matches!(
+                    s.deref().deref(),
+                    Statement::Assignment(_, e) if matches!(e.deref(), Expr::Call(..))
+                )
+            ));
+        }
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        assert!(matches!(
+            ctx.get("f"),
+            Some(Type::Callable(CallableType { args_types, .. })) if args_types.len() == 1
+        ));
+        assert!(matches!(ctx.get("y"), Some(Type::Matrix { .. })));
+        assert_eq!(
+            Some(&Type::Callable(CallableType {
+                args_types: vec![],
+                return_type: Box::new(Type::Matrix {
+                    size: Some((1, 2)),
+                    ty: SimpleType::Double
+                })
+            })),
+            ctx.get("h")
+        );
+    }
+
+    #[test]
+    fn control_flow_blocks() {
+        let actual = parse(
+            "switch x\n  case 1\n    y = 1;\n  case {2, 'two'}\n    y = 2;\n\
+             otherwise\n    y = 3;\nend\n\
+             try\n  error('oops');\ncatch err\n  msg = err.message;\nend_try_catch\n\
+             unwind_protect\n  f = 1;\nunwind_protect_cleanup\n  f = 0;\nend_unwind_protect",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            assert!(matches!(
+                v[0].deref(),
+                Statement::Switch(_, cases, Some(_)) if cases.len() == 2
+                    && cases[1].0.as_ref().case_values().len() == 2
+            ));
+            assert!(matches!(
+                v[1].deref(),
+                Statement::Try(_, Some(n), Some(_)) if n.deref() == "err"
+            ));
+            assert!(matches!(v[2].deref(), Statement::UnwindProtect(..)));
+        }
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        assert!(matches!(
+            ctx.get("err"),
+            Some(Type::Struct(s))
+                if s.field("message") == Some(&Type::SimpleType(SimpleType::String))
+        ));
+        assert_eq!(Some(&Type::SimpleType(SimpleType::String)), ctx.get("msg"));
+        assert!(ctx.get("f").is_some());
+    }
+
+    #[test]
+    fn line_continuation() {
+        let actual = parse(
+            "a = 1, b = 2;\nif a, c = 1, end\nx = [1 2 ... first row\n     3 4];\n\
+             y = a + ... % it's continued\n    b;\nz = sum(x, \\\n        2);",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        if let Statement::Block(v) = actual.deref() {
+            assert_eq!(7, v.len());
+            assert!(matches!(v[0].deref(), Statement::Assignment(..)));
+            assert!(matches!(v[1].deref(), Statement::IgnoreOutput(..)));
+            assert!(matches!(v[2].deref(), Statement::If(..)));
+            assert_eq!(
+                vec![CommentKind::Line],
+                v[4].comments().iter().map(|c| c.kind).collect::<Vec<_>>()
+            );
+        }
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 4)),
+                ty: SimpleType::Double
+            }),
+            ctx.get("x")
+        );
+        assert!(ctx.get("z").is_some());
+    }
+
+    #[test]
+    fn number_literals() {
+        let actual = parse(
+            "a = .5;\nb = 0x1F;\nc = 0b1_0000_0000;\nd = -3i;\ne = [2.5e-3j 1];\n\
+             f = 0x1Fs16;\ng = 1_000d3;\nh = 0xFFs8;",
+        );
+        println!("{:#?}", actual);
+        let errors = actual.as_ref().get_errors();
+        assert_eq!(1, errors.len());
+        assert_eq!("Integer literal out of range for int8", *errors[0]);
+        let ctx = HashMap::new();
+        actual.add_bindings(ctx.pin());
+        let ctx = ctx.pin();
+        let scalar = |ty| Some(Type::Matrix { size: Some((1, 1)), ty });
+        assert_eq!(scalar(SimpleType::Double).as_ref(), ctx.get("a"));
+        assert_eq!(
+            scalar(SimpleType::Integer(IntegerType::UInt8)).as_ref(),
+            ctx.get("b")
+        );
+        assert_eq!(
+            scalar(SimpleType::Integer(IntegerType::UInt16)).as_ref(),
+            ctx.get("c")
+        );
+        assert_eq!(scalar(SimpleType::Complex).as_ref(), ctx.get("d"));
+        assert_eq!(
+            Some(&Type::Matrix {
+                size: Some((1, 2)),
+                ty: SimpleType::Complex
+            }),
+            ctx.get("e")
+        );
+        assert_eq!(
+            scalar(SimpleType::Integer(IntegerType::Int16)).as_ref(),
+            ctx.get("f")
+        );
+        if let Statement::Block(v) = actual.deref() {
+            let value = |i: usize| match v[i].deref() {
+                Statement::IgnoreOutput(s) => match s.deref().deref() {
+                    Statement::Assignment(_, e) => e.get_matrix().map(|m| m.data[0]),
+                    _ => None,
+                },
+                _ => None,
+            };
+            assert_eq!(Some(0.5), value(0));
+            assert_eq!(Some(256.0), value(2));
+            assert_eq!(Some(1e6), value(6));
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        let actual = parse(
+            "a = 'it''s C:\\path\\n';\nb = \"tab\\t\\x41\\102 \\\"q\\\" \"\"d\"\"\";\nc = [a' b'];",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let literals = if let Statement::Block(v) = actual.deref() {
+            v.iter()
+                .filter_map(|s| match s.deref() {
+                    Statement::IgnoreOutput(s) => match s.deref().deref() {
+                        Statement::Assignment(_, e) => match e.deref() {
+                            Expr::Matrix(m) => match m.data[0].deref() {
+                                Expr::LitString(s) => Some(s.clone()),
+                                _ => None,
+                            },
+                            _ => None,
+                        },
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        } else {
+            unreachable!()
+        };
+        assert_eq!(2, literals.len());
+        assert_eq!("it's C:\\path\\n", literals[0].value);
+        assert_eq!("'it''s C:\\path\\n'", literals[0].raw);
+        assert!(!literals[0].is_double_quoted());
+        assert_eq!("tab\tAB \"q\" \"d\"", literals[1].value);
+        assert!(literals[1].is_double_quoted());
+    }
+
+    #[test]
+    fn command_syntax() {
+        let actual = parse(
+            "pkg load signal\nhold on;\nclose all, clear all % cleanup\n\
+             warning off 'Octave:some-id'\na -1\na - 1\nb = 2\ndisp (b)",
+        );
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let v = match actual.deref() {
+            Statement::Block(v) => v,
+            _ => unreachable!(),
+        };
+        let command = |s: &Statement| {
+            let e = match s {
+                Statement::Expr(e) => e.clone(),
+                Statement::IgnoreOutput(s) => match s.deref().deref() {
+                    Statement::Expr(e) => e.clone(),
+                    _ => return vec![],
+                },
+                _ => return vec![],
+            };
+            match e.deref() {
+                Expr::Command(name, args) => std::iter::once(name.root_name().unwrap().to_string())
+                    .chain(args.iter().map(|a| match a.deref() {
+                        Expr::LitString(s) => s.value.clone(),
+                        e => panic!("Unexpected command argument {:?}", e),
+                    }))
+                    .collect(),
+                _ => vec![],
+            }
+        };
+        assert_eq!(vec!["pkg", "load", "signal"], command(&v[0]));
+        assert_eq!(vec!["hold", "on"], command(&v[1]));
+        assert_eq!(vec!["close", "all"], command(&v[2]));
+        assert_eq!(vec!["clear", "all"], command(&v[3]));
+        assert_eq!(vec!["warning", "off", "Octave:some-id"], command(&v[4]));
+        assert_eq!(vec!["a", "-1"], command(&v[5]));
+        assert!(matches!(
+            v[6].deref(),
+            Statement::Expr(e) if matches!(e.deref(), Expr::Op(Op::Sub, ..))
+        ));
+        assert!(matches!(v[7].deref(), Statement::Assignment(..)));
+        assert!(matches!(
+            v[8].deref(),
+            Statement::Expr(e) if matches!(
+                e.deref(),
+                Expr::Call(_, args) if matches!(args[0].deref(), Expr::Identifier(_))
+            )
+        ));
+    }
+
+    #[test]
+    fn command_syntax_variables() {
+        let actual = parse("x = 5;\nx -1\nfunction f(y)\n  y -1;\n  z -1;\nend");
+        println!("{:#?}", actual);
+        assert_eq!(0, actual.as_ref().get_errors().len());
+        let sexp = crate::sexp::stmt_to_sexp(&actual);
+        assert!(sexp.contains("(- x 1)"), "{}", sexp);
+        assert!(sexp.contains("(- y 1)"), "{}", sexp);
+        assert!(sexp.contains("(command z \"-1\")"), "{}", sexp);
+    }
+
+    #[test]
+    fn byte_offsets() {
+        let text = "s = 'été 🎉'; % déjà\nx = s;";
+        let actual = parse(text);
+        println!("{:#?}", actual);
+        let v = match actual.deref() {
+            Statement::Block(v) => v,
+            _ => unreachable!(),
+        };
+        let x = &v[1];
+        assert_eq!("x = s;", &text[x.range()]);
+        assert_eq!(2, x.span().start.line);
+        assert_eq!(lsp_types::Position::new(1, 0), x.span().start.to_lsp(text));
+        let comment = &v[0].comments()[0];
+        assert_eq!(
+            "% déjà",
+            &text[comment.span.start.offset..comment.span.end.offset]
+        );
+        // `é` is one UTF-16 code unit and `🎉` is two
+        assert_eq!(
+            lsp_types::Range::new(
+                lsp_types::Position::new(0, 14),
+                lsp_types::Position::new(0, 20)
+            ),
+            range_to_lsp(text, comment.span.clone())
+        );
+        assert_eq!(
+            comment.span.start,
+            Position::from_lsp(text, lsp_types::Position::new(0, 14))
+        );
+        assert_eq!(
+            Position::at_offset(text, text.len()),
+            Position::from_lsp(text, lsp_types::Position::new(5, 0))
+        );
+    }
+
+    #[test]
+    fn nested_errors() {
+        let actual = parse("x = 1 + 0x1FFu8;\ny = f(2, 0b111111111u8);\nz = @(a) a * 0xFFFs8;");
+        println!("{:#?}", actual);
+        let errors = actual.as_ref().get_errors();
+        assert_eq!(
+            vec![1, 2, 3],
+            errors.iter().map(|e| e.span.start.line).collect::<Vec<_>>()
+        );
+        assert_eq!("Integer literal out of range for uint8", errors[0].data);
+    }
+
+    #[test]
+    fn edits_reuse_nodes() {
+        let mut text = String::from(
+            "x = 1;\ny = x(1);\n% note\nif x, z = y(2), end % c\nw = z -1\nfor k = 1:3\n  \
+             q(k) = k; % q\nend\nq(1)\nfunction y = f(a)\n  if a % one\n    y = a(1);\n  \
+             end % if\nend\n",
+        );
+        let snippets = [
+            "",
+            "1",
+            "\n",
+            ";",
+            "end\n",
+            "if x\n",
+            "function h\n",
+            "x = [1\n",
+            "disp y",
+            "(",
+            " % c\n",
+            "...\n",
+            "%{\n",
+            "x = 1;\n",
+            "y = @sin;",
+            "catch e\n",
+            "z -1\n",
+        ];
+        let mut cst = SyntaxNode::parse(&text);
+        let mut ast = from_cst(&cst);
+        // Simple linear congruential generator, to get the same edits on every run
+        let mut seed: u64 = 3;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (seed >> 33) as usize % n.max(1)
+        };
+        for _ in 0..150 {
+            let start = random(text.len() + 1);
+            let end = (start + random(4)).min(text.len());
+            let snippet = snippets[random(snippets.len())];
+            text.replace_range(start..end, snippet);
+            let edited = cst.edit(start..end, snippet);
+            ast = from_edit(&cst, ast, &edited);
+            assert_eq!(from_cst(&edited), ast, "{:?}", text);
+            cst = edited;
+        }
+    }
+
+    #[test]
+    fn edit_keeps_comments() {
+        // Comments within unchanged statements that belong to the statement containing them, or to
+        // the last statement of a block ending on the same line
+        let cases = [
+            ("if x, ;z = y(2);, ed % c\nd o\nendif\n", 11..12, ""),
+            ("d\"\nfor z=c)end;i%", 0..2, "y = @sin;"),
+            ("n 2 @()d\nif y z...;\nend%", 3..4, "x = [1\n"),
+            ("\nfunction l\n1function%", 0..0, ";"),
+            ("n nd;function h\n1end%", 2..4, "@(x) x"),
+        ];
+        for (text, range, snippet) in cases {
+            let cst = SyntaxNode::parse(text);
+            let edited = cst.edit(range, snippet);
+            assert_eq!(
+                from_cst(&edited),
+                from_edit(&cst, from_cst(&cst), &edited),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn edit_resolves_reused_statements() {
+        let text = "y = x(1); % x\nfunction z = f\n  z = x(2);\nend\n";
+        let cst = SyntaxNode::parse(text);
+        let edited = cst.edit(0..0, "x = 1;\n");
+        let ast = from_edit(&cst, from_cst(&cst), &edited);
+        assert_eq!(
+            "(block
+  (quiet (= x 1))
+  (quiet (= y (index x 1)))
+  (function f (z) () (block
+    (quiet (= z (call x 2)))))
+  eoi)",
+            stmt_to_sexp(&ast)
+        );
+        if let Statement::Block(v) = ast.deref() {
+            assert_eq!(2, v[1].span.start.line);
+            assert_eq!("% x", v[1].comments[0].text);
+        } else {
+            panic!("Expected a block")
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize() {
+        let actual = parse("% one\nx = 0x1u8;");
+        let json = serde_json::to_value(&actual).unwrap();
+        println!("{:#}", json);
+        let stmt = &json["data"]["Block"][0];
+        assert_eq!(6, stmt["span"]["start"]["offset"]);
+        assert_eq!("% one", stmt["comments"][0]["text"]);
+        assert_eq!(
+            serde_json::json!({ "value": 1, "ty": "UInt8", "raw": "0x1u8" }),
+            stmt["data"]["IgnoreOutput"]["data"]["Assignment"][1]["data"]["Matrix"]["data"][0]
+                ["data"]["LitInteger"]
+        );
+    }
 }
@@ -0,0 +1,679 @@
+//! Rendering of the AST back into Octave source. Expressions only get the parentheses needed to
+//! parse back into the same tree, and statements are laid out one per line with their blocks
+//! indented. Comments attached to statements and blocks are kept, along with single blank lines
+//! between statements. The layout can be tuned with a `Style`. Trees with syntax errors are
+//! refused, as their error nodes don't keep the source they were parsed from.
+use crate::ast::{Expr, Op, Statement};
+use crate::node::{Comment, CommentKind, Node};
+use crate::value::Matrix;
+use crate::visit::{walk_expr, Visitor};
+use std::borrow::Borrow;
+use std::iter;
+use thiserror::Error;
+
+/// Binding strengths of the expressions which aren't binary operators, on the scale of
+/// `level(op)`.
+const ANON_FUNCTION: u8 = 0;
+const RANGE: u8 = 11;
+const PREFIX: u8 = 15;
+const POSTFIX: u8 = 18;
+
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum PrintError {
+    #[error("Cannot print code with syntax errors")]
+    SyntaxErrors,
+}
+
+/// Layout choices left open by the syntax.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Style {
+    /// Width of an indentation level, in spaces
+    pub indent_width: usize,
+    /// Indent with one tab per level instead of spaces
+    pub tabs: bool,
+    /// Surround binary operators with spaces, except for power operators and field accesses
+    pub operator_spacing: bool,
+    pub matrix_separator: MatrixSeparator,
+    pub end_keyword: EndKeyword,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            indent_width: 2,
+            tabs: false,
+            operator_spacing: true,
+            matrix_separator: MatrixSeparator::Comma,
+            end_keyword: EndKeyword::End,
+        }
+    }
+}
+
+/// Separator between the elements of a row of a matrix or cell literal.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum MatrixSeparator {
+    /// `[1, 2; 3, 4]`
+    Comma,
+    /// `[1 2; 3 4]`
+    Space,
+}
+
+/// Keyword closing compound statements.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum EndKeyword {
+    /// `end` for every statement
+    End,
+    /// The keyword specific to each statement, such as `endif` or `end_try_catch`
+    Specific,
+}
+
+/// Source of a statement in the default style, ending with a newline.
+pub fn stmt_to_source(stmt: &Node<Statement>) -> Result<String, PrintError> {
+    Style::default().stmt_to_source(stmt)
+}
+
+/// Source of an expression in the default style.
+pub fn expr_to_source(expr: &Expr) -> Result<String, PrintError> {
+    Style::default().expr_to_source(expr)
+}
+
+impl Style {
+    /// Source of a statement, ending with a newline.
+    pub fn stmt_to_source(&self, stmt: &Node<Statement>) -> Result<String, PrintError> {
+        if !stmt.as_ref().get_errors().is_empty() {
+            return Err(PrintError::SyntaxErrors);
+        }
+        let mut out = String::new();
+        match &stmt.data {
+            Statement::Block(v) => self.write_block(&mut out, v, &stmt.comments, 0),
+            _ => self.write_node(&mut out, stmt, 0),
+        }
+        Ok(out)
+    }
+
+    /// Source of top-level statements, ending with a newline.
+    pub fn block_to_source(&self, stmts: &[Node<Statement>]) -> Result<String, PrintError> {
+        if stmts.iter().any(|s| !s.as_ref().get_errors().is_empty()) {
+            return Err(PrintError::SyntaxErrors);
+        }
+        let mut out = String::new();
+        self.write_block(&mut out, stmts, &[], 0);
+        Ok(out)
+    }
+
+    /// Source of an expression.
+    pub fn expr_to_source(&self, expr: &Expr) -> Result<String, PrintError> {
+        if has_errors(expr) {
+            return Err(PrintError::SyntaxErrors);
+        }
+        Ok(self.expr(expr))
+    }
+
+    fn expr(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Error(_) => unreachable!("error nodes are refused before printing"),
+            // Words of command syntax are kept unquoted
+            Expr::LitString(s) => s.raw.clone(),
+            Expr::LitNumber(n) | Expr::LitComplex(n) => n.raw.clone(),
+            Expr::LitInteger(n) => n.raw.clone(),
+            Expr::Identifier(name) => name.clone(),
+            Expr::Matrix(m) if is_scalar_literal(m) => self.expr(&m.data[0]),
+            Expr::Matrix(m) => format!("[{}]", self.rows(m)),
+            Expr::Cell(m) => format!("{{{}}}", self.rows(m)),
+            Expr::Op(Op::Access, a, b) => {
+                format!("{}.{}", self.operand(a, POSTFIX), self.expr(b))
+            }
+            Expr::Op(Op::DynamicAccess, a, b) => {
+                format!("{}.({})", self.operand(a, POSTFIX), self.expr(b))
+            }
+            Expr::Op(op @ Op::Pow, a, b) | Expr::Op(op @ Op::ElemPow, a, b) => {
+                // The exponent may have prefix operators, as in `2^-x`
+                let rhs = if is_prefixed(b) {
+                    self.expr(b)
+                } else {
+                    self.operand(b, POSTFIX)
+                };
+                format!("{}{}{}", self.operand(a, level(*op)), op.symbol(), rhs)
+            }
+            // All binary operators are left-associative
+            Expr::Op(op, a, b) => {
+                let lhs = self.operand(a, level(*op));
+                let rhs = self.operand(b, level(*op) + 1);
+                if self.operator_spacing {
+                    format!("{} {} {}", lhs, op.symbol(), rhs)
+                } else if op.symbol().ends_with(['+', '-']) && rhs.starts_with(op.symbol()) {
+                    // `a-(-1)` isn't `a--1`
+                    format!("{}{}({})", lhs, op.symbol(), rhs)
+                } else {
+                    format!("{}{}{}", lhs, op.symbol(), rhs)
+                }
+            }
+            Expr::Unary(op, e) if op.is_postfix() => {
+                // A quote right after a string would be read as part of it
+                if is_string(e) {
+                    format!("({}){}", self.expr(e), op.symbol())
+                } else {
+                    format!("{}{}", self.operand(e, POSTFIX), op.symbol())
+                }
+            }
+            Expr::Unary(op, e) => {
+                let e = self.operand(e, PREFIX);
+                // `- -x` isn't `--x`
+                if op.symbol().ends_with(['+', '-']) && e.starts_with(['+', '-']) {
+                    format!("{} {}", op.symbol(), e)
+                } else {
+                    format!("{}{}", op.symbol(), e)
+                }
+            }
+            Expr::Incr(e) => format!("{}++", self.operand(e, POSTFIX)),
+            Expr::Decr(e) => format!("{}--", self.operand(e, POSTFIX)),
+            Expr::Range(s, st, e) => iter::once(s)
+                .chain(st)
+                .chain(iter::once(e))
+                .map(|n| self.operand(n, RANGE + 1))
+                .collect::<Vec<_>>()
+                .join(":"),
+            Expr::Call(c, args) | Expr::Index(c, args) => {
+                format!("{}({})", self.operand(c, POSTFIX), self.list(args, ", "))
+            }
+            Expr::CellIndex(c, args) => {
+                format!("{}{{{}}}", self.operand(c, POSTFIX), self.list(args, ", "))
+            }
+            Expr::Command(c, args) => iter::once(&*c.data)
+                .chain(args.iter().map(|a| &a.data))
+                .map(|e| self.expr(e))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Expr::AnonFunction(params, body) => {
+                format!("@({}) {}", names(params), self.expr(body))
+            }
+            Expr::Handle(name) => format!("@{}", name.data),
+            Expr::Colon => ":".into(),
+            Expr::End => "end".into(),
+        }
+    }
+
+    /// Source of an operand, parenthesized if it binds looser than `min`.
+    fn operand(&self, expr: &Expr, min: u8) -> String {
+        if precedence(expr) < min {
+            format!("({})", self.expr(expr))
+        } else {
+            self.expr(expr)
+        }
+    }
+
+    fn rows(&self, m: &Matrix<Node<Expr>>) -> String {
+        (0..m.height())
+            .map(|i| {
+                let row = &m[i];
+                // The body of an anonymous function would take in the next element
+                let spaced = self.matrix_separator == MatrixSeparator::Space
+                    && !row.iter().any(|e| matches!(e.data, Expr::AnonFunction(..)));
+                self.list(row, if spaced { " " } else { ", " })
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn list(&self, exprs: &[Node<Expr>], separator: &str) -> String {
+        exprs
+            .iter()
+            .map(|e| self.expr(e))
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    fn indent(&self, level: usize) -> String {
+        if self.tabs {
+            "\t".repeat(level)
+        } else {
+            " ".repeat(level * self.indent_width)
+        }
+    }
+
+    /// Line closing a compound statement, `specific` being its own keyword.
+    fn end(&self, pad: &str, specific: &str) -> String {
+        match self.end_keyword {
+            EndKeyword::End => format!("{}end", pad),
+            EndKeyword::Specific => format!("{}{}", pad, specific),
+        }
+    }
+
+    /// Writes a statement of a block along with its comments, starting on a new line.
+    fn write_node(&self, out: &mut String, node: &Node<Statement>, indent: usize) {
+        let pad = self.indent(indent);
+        for c in node.leading_comments() {
+            out.push_str(&pad);
+            out.push_str(&c.text);
+            out.push('\n');
+        }
+        let (header, trailing): (Vec<&Comment>, Vec<&Comment>) =
+            node.trailing_comments().partition(|c| {
+                c.kind == CommentKind::Line
+                    && c.span.start.line == node.span.start.line
+                    && node.span.end.line > node.span.start.line
+            });
+        let mut text = String::new();
+        self.write_stmt(&mut text, &node.data, indent);
+        if text.is_empty() {
+            // End of the input, which only holds the comments ending it
+            return;
+        }
+        let mut lines = text.lines();
+        if let Some(first) = lines.next() {
+            out.push_str(&pad);
+            out.push_str(first);
+            for c in header {
+                out.push(' ');
+                out.push_str(&c.text);
+            }
+            out.push('\n');
+        }
+        for line in lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+        let (same_line, after): (Vec<&Comment>, Vec<&Comment>) = trailing
+            .into_iter()
+            .partition(|c| c.kind == CommentKind::Line && c.span.start.line == node.span.end.line);
+        if !same_line.is_empty() {
+            out.pop();
+            for c in same_line {
+                out.push(' ');
+                out.push_str(&c.text);
+            }
+            out.push('\n');
+        }
+        for c in after {
+            out.push_str(&pad);
+            out.push_str(&c.text);
+            out.push('\n');
+        }
+    }
+
+    /// Writes the statements of a block followed by the comments ending it, keeping single blank
+    /// lines between them.
+    fn write_block(
+        &self,
+        out: &mut String,
+        stmts: &[Node<Statement>],
+        trailing: &[Comment],
+        indent: usize,
+    ) {
+        let mut previous_end: Option<usize> = None;
+        for s in stmts {
+            let start = s
+                .leading_comments()
+                .map(|c| c.span.start.line)
+                .chain(iter::once(s.span.start.line))
+                .min()
+                .unwrap();
+            if matches!(previous_end, Some(end) if start > end + 1) {
+                out.push('\n');
+            }
+            self.write_node(out, s, indent);
+            previous_end = Some(s.span.end.line);
+        }
+        let pad = self.indent(indent);
+        for c in trailing {
+            if matches!(previous_end, Some(end) if c.span.start.line > end + 1) {
+                out.push('\n');
+            }
+            out.push_str(&pad);
+            out.push_str(&c.text);
+            out.push('\n');
+            previous_end = Some(c.span.end.line);
+        }
+    }
+
+    /// Writes the body of a compound statement, one level deeper than the statement.
+    fn write_body<S: Borrow<Statement>>(&self, out: &mut String, body: &Node<S>, indent: usize) {
+        match body.data.borrow() {
+            Statement::Block(v) => self.write_block(out, v, &body.comments, indent + 1),
+            stmt => {
+                out.push_str(&self.indent(indent + 1));
+                self.write_stmt(out, stmt, indent + 1);
+                out.push('\n');
+            }
+        }
+    }
+
+    /// Writes a statement without its comments. Lines after the first are indented, while the
+    /// first one is left for the caller to indent.
+    fn write_stmt(&self, out: &mut String, stmt: &Statement, indent: usize) {
+        let pad = self.indent(indent);
+        match stmt {
+            Statement::Error(_) => unreachable!("error nodes are refused before printing"),
+            Statement::IgnoreOutput(s) => {
+                self.write_stmt(out, s, indent);
+                out.push(';');
+            }
+            Statement::Expr(e) => out.push_str(&self.expr(e)),
+            Statement::Assignment(t, e) => {
+                out.push_str(&format!("{} = {}", self.expr(t), self.expr(e)))
+            }
+            Statement::AugAssignment(name, op, e) => {
+                out.push_str(&format!("{} {}= {}", name, op.symbol(), self.expr(e)))
+            }
+            Statement::Block(v) => {
+                let mut block = String::new();
+                self.write_block(&mut block, v, &[], indent);
+                out.push_str(block.trim_start().trim_end_matches('\n'));
+            }
+            Statement::If(branches, otherwise) => {
+                for (i, (c, b)) in branches.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(&pad);
+                        out.push_str("else");
+                    }
+                    out.push_str(&format!("if {}\n", self.expr(c)));
+                    self.write_body(out, b, indent);
+                }
+                if let Some(b) = otherwise {
+                    out.push_str(&format!("{}else\n", pad));
+                    self.write_body(out, b, indent);
+                }
+                out.push_str(&self.end(&pad, "endif"));
+            }
+            Statement::Switch(e, cases, otherwise) => {
+                let case_pad = self.indent(indent + 1);
+                out.push_str(&format!("switch {}\n", self.expr(e)));
+                for (c, b) in cases {
+                    out.push_str(&format!("{}case {}\n", case_pad, self.expr(c)));
+                    self.write_body(out, b, indent + 1);
+                }
+                if let Some(b) = otherwise {
+                    out.push_str(&format!("{}otherwise\n", case_pad));
+                    self.write_body(out, b, indent + 1);
+                }
+                out.push_str(&self.end(&pad, "endswitch"));
+            }
+            Statement::Try(b, err, c) => {
+                out.push_str("try\n");
+                self.write_body(out, b, indent);
+                if err.is_some() || c.is_some() {
+                    out.push_str(&pad);
+                    out.push_str("catch");
+                    if let Some(err) = err {
+                        out.push(' ');
+                        out.push_str(err);
+                    }
+                    out.push('\n');
+                    if let Some(c) = c {
+                        self.write_body(out, c, indent);
+                    }
+                }
+                out.push_str(&self.end(&pad, "end_try_catch"));
+            }
+            Statement::UnwindProtect(b, c) => {
+                out.push_str("unwind_protect\n");
+                self.write_body(out, b, indent);
+                out.push_str(&format!("{}unwind_protect_cleanup\n", pad));
+                self.write_body(out, c, indent);
+                out.push_str(&self.end(&pad, "end_unwind_protect"));
+            }
+            Statement::Function(outputs, name, inputs, b) => {
+                out.push_str("function ");
+                match outputs.len() {
+                    0 => {}
+                    1 => out.push_str(&format!("{} = ", outputs[0].data)),
+                    _ => out.push_str(&format!("[{}] = ", names(outputs))),
+                }
+                out.push_str(&name.data);
+                if !inputs.is_empty() {
+                    out.push_str(&format!("({})", names(inputs)));
+                }
+                out.push('\n');
+                self.write_body(out, b, indent);
+                out.push_str(&self.end(&pad, "endfunction"));
+            }
+            Statement::For(i, e, b) => {
+                out.push_str(&format!("for {} = {}\n", i, self.expr(e)));
+                self.write_body(out, b, indent);
+                out.push_str(&self.end(&pad, "endfor"));
+            }
+            Statement::While(e, b) => {
+                out.push_str(&format!("while {}\n", self.expr(e)));
+                self.write_body(out, b, indent);
+                out.push_str(&self.end(&pad, "endwhile"));
+            }
+            Statement::DoUntil(b, e) => {
+                out.push_str("do\n");
+                self.write_body(out, b, indent);
+                out.push_str(&format!("{}until {}", pad, self.expr(e)));
+            }
+            Statement::Break => out.push_str("break"),
+            Statement::Continue => out.push_str("continue"),
+            Statement::EOI => {}
+        }
+    }
+}
+
+/// Binding strength of a binary operator, leaving room for the other kinds of expressions.
+fn level(op: Op) -> u8 {
+    op.precedence() * 2
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::AnonFunction(..) => ANON_FUNCTION,
+        Expr::Op(op, ..) => level(*op),
+        Expr::Range(..) => RANGE,
+        Expr::Unary(op, _) if !op.is_postfix() => PREFIX,
+        // Negative literals are written with a prefix operator
+        Expr::LitNumber(n) | Expr::LitComplex(n) if n.raw.starts_with('-') => PREFIX,
+        Expr::Matrix(m) if is_scalar_literal(m) => precedence(&m.data[0]),
+        _ => POSTFIX,
+    }
+}
+
+/// Whether the expression is prefix operators applied to a postfix expression, which power
+/// operators accept as their exponent.
+fn is_prefixed(expr: &Expr) -> bool {
+    match expr {
+        Expr::Unary(op, e) if !op.is_postfix() => is_prefixed(e) || precedence(e) >= POSTFIX,
+        Expr::Matrix(m) if is_scalar_literal(m) => is_prefixed(&m.data[0]),
+        Expr::LitNumber(_) | Expr::LitComplex(_) => true,
+        _ => false,
+    }
+}
+
+fn is_string(expr: &Expr) -> bool {
+    match expr {
+        Expr::LitString(_) => true,
+        Expr::Matrix(m) => is_scalar_literal(m) && is_string(&m.data[0]),
+        _ => false,
+    }
+}
+
+/// Whether the matrix is a single literal, which is how the parser represents scalar literals.
+fn is_scalar_literal(m: &Matrix<Node<Expr>>) -> bool {
+    m.len() == 1
+        && matches!(
+            m.data[0].data,
+            Expr::LitNumber(_) | Expr::LitComplex(_) | Expr::LitInteger(_) | Expr::LitString(_)
+        )
+}
+
+fn names(names: &[Node<String>]) -> String {
+    names
+        .iter()
+        .map(|n| n.data.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Whether the expression has error nodes, which can't be printed.
+fn has_errors(expr: &Expr) -> bool {
+    struct Errors(bool);
+
+    impl<'a> Visitor<'a> for Errors {
+        fn visit_expr(&mut self, node: Node<&'a Expr>) {
+            self.0 |= matches!(node.data, Expr::Error(_));
+            walk_expr(self, node.data)
+        }
+    }
+
+    let mut errors = Errors(matches!(expr, Expr::Error(_)));
+    walk_expr(&mut errors, expr);
+    errors.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expr_to_source, stmt_to_source, PrintError, Style};
+    use crate::ast::Expr;
+    use crate::parser::parse;
+    use crate::sexp::stmt_to_sexp;
+
+    #[test]
+    fn minimal_parentheses() {
+        let cases = [
+            ("x = (a + b) * c;", "x = (a + b) * c;"),
+            ("x = a + (b * c);", "x = a + b * c;"),
+            ("x = (a - b) - c;", "x = a - b - c;"),
+            ("x = a - (b - c);", "x = a - (b - c);"),
+            ("x = -(2 ^ 2);", "x = -2^2;"),
+            ("x = (-2) ^ 2;", "x = (-2)^2;"),
+            ("x = 2 ^ (-y);", "x = 2^-y;"),
+            ("x = (2 ^ 3) ^ 4;", "x = 2^3^4;"),
+            ("x = 2 ^ (3 ^ 4);", "x = 2^(3^4);"),
+            ("x = (a')';", "x = a'';"),
+            ("x = (-a)';", "x = (-a)';"),
+            ("x = ('it''s')';", "x = ('it''s')';"),
+            ("x = - (-a);", "x = - -a;"),
+            ("x = (1:3) == y;", "x = 1:3 == y;"),
+            ("x = (a || b) && c;", "x = (a || b) && c;"),
+            ("x = a | (b & c);", "x = a | b & c;"),
+            ("x = (1 + 2):(n - 1);", "x = 1 + 2:n - 1;"),
+            ("x = (@(t) t + 1) + 1;", "x = (@(t) t + 1) + 1;"),
+            ("f = @(t) (t + 1) * 2;", "f = @(t) (t + 1) * 2;"),
+            ("x = s.a(1).(name){end};", "x = s.a(1).(name){end};"),
+            ("x = [1 -2; 3 (4)]';", "x = [1, -2; 3, 4]';"),
+            ("hold on", "hold on"),
+            (
+                "y = {@sin, 0x1Fu8, 2.5i, \"a\\tb\"};",
+                "y = {@sin, 0x1Fu8, 2.5i, \"a\\tb\"};",
+            ),
+        ];
+        for (input, expected) in cases.iter() {
+            assert_eq!(
+                format!("{}\n", expected),
+                stmt_to_source(&parse(input)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn compact_operators() {
+        let style = Style {
+            operator_spacing: false,
+            ..Style::default()
+        };
+        let cases = [
+            ("x = a - (-1);", "x = a-(-1);"),
+            ("x = a + (+b);", "x = a+(+b);"),
+            ("x = a - (--b);", "x = a-(--b);"),
+            ("x = a + (-b);", "x = a+-b;"),
+            ("x = [a - (-1)];", "x = [a-(-1)];"),
+        ];
+        for (input, expected) in cases.iter() {
+            let ast = parse(input);
+            let printed = style.stmt_to_source(&ast).unwrap();
+            assert_eq!(format!("{}\n", expected), printed);
+            assert_eq!(stmt_to_sexp(&ast), stmt_to_sexp(&parse(&printed)));
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let input = "% header\n\
+                     function [a, b] = f(x, y) % two outputs\n\
+                     a = x; b = y;\n\n\n\
+                     if a, b = 1, elseif b, a = 2, else disp(a), end\n\
+                     end\n\
+                     switch x\ncase {1, 2}\ny = 3;\notherwise\ny = 4;\nend\n\
+                     try, error('no'), catch err, disp(err.message), end\n\
+                     for i = 1:10\n  while true, break, end\n  do\n    i++;\n  until i > 5\nend\n\
+                     %{\nblock\n%}\n";
+        let expected = "% header
+function [a, b] = f(x, y) % two outputs
+  a = x;
+  b = y;
+
+  if a
+    b = 1
+  elseif b
+    a = 2
+  else
+    disp(a)
+  end
+end
+switch x
+  case {1, 2}
+    y = 3;
+  otherwise
+    y = 4;
+end
+try
+  error('no')
+catch err
+  disp(err.message)
+end
+for i = 1:10
+  while true
+    break
+  end
+  do
+    i++;
+  until i > 5
+end
+%{
+block
+%}
+";
+        assert_eq!(expected, stmt_to_source(&parse(input)).unwrap());
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            "x = [1 2; 3 4]';\ny = x(end, :) .* 2 - ~x;\nz = {x, 'a'}{1}(2);\n",
+            "function varargout = f(varargin)\n  unwind_protect\n    s.a.(k) = -x.^-2;\n  \
+             unwind_protect_cleanup\n    clear all\n  end_unwind_protect\nend\n",
+            "t = a'' + ('s')';\ng = @(a, b) a' * b + @() 1;\nc = !(a == b) | a < b & b >= 1e-3;\n",
+        ];
+        for input in inputs.iter() {
+            let ast = parse(input);
+            let printed = stmt_to_source(&ast).unwrap();
+            let reparsed = parse(&printed);
+            assert_eq!(0, reparsed.as_ref().get_errors().len());
+            assert_eq!(stmt_to_sexp(&ast), stmt_to_sexp(&reparsed));
+            assert_eq!(printed, stmt_to_source(&reparsed).unwrap());
+        }
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let ast = parse("x = 1;\ny = (2 +;\n");
+        assert_ne!(0, ast.as_ref().get_errors().len());
+        assert_eq!(Err(PrintError::SyntaxErrors), stmt_to_source(&ast));
+        let error = Expr::Error("2 +".to_string());
+        assert_eq!(Err(PrintError::SyntaxErrors), expr_to_source(&error));
+    }
+}
@@ -0,0 +1,170 @@
+//! Variables of the scripts and functions of a document, with where they're defined and read.
+use crate::ast::{Expr, Op, Statement};
+use crate::node::{Node, Position};
+use crate::visit::{walk_expr, walk_stmt, Visitor};
+use std::ops::Range;
+
+/// Functions reading or writing variables by name, which hide how variables are used.
+const DYNAMIC: &[&str] = &["eval", "evalin", "assignin", "load"];
+
+/// Top level of a script, or body of a function, each having their own variables.
+#[derive(Debug, Default)]
+pub(crate) struct Scope<'a> {
+    pub function: bool,
+    pub definitions: Vec<Definition<'a>>,
+    /// Identifiers read in the scope, which are either variables or functions
+    pub reads: Vec<(&'a str, Range<Position>)>,
+    /// Outputs of the function, read by its caller
+    pub outputs: Vec<&'a str>,
+    /// Whether the scope calls one of the `DYNAMIC` functions
+    pub dynamic: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Definition<'a> {
+    pub name: &'a str,
+    pub kind: DefinitionKind,
+    pub span: Range<Position>,
+    /// Where the variable starts being defined, e.g. after the right-hand side of an assignment
+    pub from: Position,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum DefinitionKind {
+    Assignment,
+    Input,
+    Loop,
+    CaughtError,
+}
+
+/// Scopes of a document, starting with its top level.
+pub(crate) fn scopes(ast: Node<&Statement>) -> Vec<Scope<'_>> {
+    let mut collector = Collector {
+        scopes: vec![Scope::default()],
+        current: 0,
+        params: vec![],
+    };
+    collector.visit_stmt(ast);
+    collector.scopes
+}
+
+struct Collector<'a> {
+    scopes: Vec<Scope<'a>>,
+    /// Index of the scope being visited
+    current: usize,
+    /// Parameters of the anonymous functions being visited, which hide variables of the scope
+    params: Vec<&'a str>,
+}
+
+impl<'a> Collector<'a> {
+    fn define(
+        &mut self,
+        name: &'a str,
+        kind: DefinitionKind,
+        span: Range<Position>,
+        from: Position,
+    ) {
+        // Ignored inputs are written `~`
+        if name != "~" {
+            self.scopes[self.current].definitions.push(Definition {
+                name,
+                kind,
+                span,
+                from,
+            });
+        }
+    }
+
+    /// Visits the target of an assignment, which defines the variable it starts with.
+    fn visit_target(&mut self, target: Node<&'a Expr>, from: Position) {
+        match *target {
+            Expr::Identifier(name) => {
+                self.define(name, DefinitionKind::Assignment, target.span(), from)
+            }
+            Expr::Index(e, args) | Expr::CellIndex(e, args) | Expr::Call(e, args) => {
+                self.visit_target(e.as_deref(), from);
+                for a in args {
+                    self.visit_expr(a.as_ref());
+                }
+            }
+            Expr::Op(Op::Access, e, _) => self.visit_target(e.as_deref(), from),
+            Expr::Op(Op::DynamicAccess, e, field) => {
+                self.visit_target(e.as_deref(), from);
+                self.visit_expr(field.as_deref());
+            }
+            _ => self.visit_expr(target),
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for Collector<'a> {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        let span = node.span();
+        match *node {
+            Statement::Assignment(target, e) => {
+                self.visit_expr(e.as_ref());
+                self.visit_target(target.as_ref(), span.end);
+            }
+            Statement::AugAssignment(name, _, e) => {
+                self.visit_expr(e.as_ref());
+                self.scopes[self.current].reads.push((name, span.clone()));
+                self.define(name, DefinitionKind::Assignment, span.clone(), span.end);
+            }
+            Statement::For(name, e, body) => {
+                self.visit_expr(e.as_ref());
+                // The loop variable has no span of its own
+                let header = span.start..e.span().start;
+                self.define(name, DefinitionKind::Loop, header, e.span().end);
+                self.visit_stmt(body.as_deref());
+            }
+            Statement::Try(body, err, catch) => {
+                self.visit_stmt(body.as_deref());
+                if let Some(err) = err {
+                    self.define(
+                        &err.data,
+                        DefinitionKind::CaughtError,
+                        err.span(),
+                        err.span().start,
+                    );
+                }
+                if let Some(catch) = catch {
+                    self.visit_stmt(catch.as_deref());
+                }
+            }
+            Statement::Function(outputs, _, inputs, body) => {
+                let parent = self.current;
+                self.scopes.push(Scope {
+                    function: true,
+                    outputs: outputs.iter().map(|o| o.data.as_str()).collect(),
+                    ..Scope::default()
+                });
+                self.current = self.scopes.len() - 1;
+                for i in inputs {
+                    self.define(&i.data, DefinitionKind::Input, i.span(), i.span().start);
+                }
+                self.visit_stmt(body.as_deref());
+                self.current = parent;
+            }
+            _ => walk_stmt(self, *node),
+        }
+    }
+
+    fn visit_expr(&mut self, node: Node<&'a Expr>) {
+        match *node {
+            Expr::Identifier(name) if !self.params.contains(&name.as_str()) => {
+                let scope = &mut self.scopes[self.current];
+                scope.dynamic |= DYNAMIC.contains(&name.as_str());
+                scope.reads.push((name, node.span()));
+            }
+            // Field names aren't variables
+            Expr::Op(Op::Access, e, _) => self.visit_expr(e.as_deref()),
+            Expr::AnonFunction(params, body) => {
+                let len = self.params.len();
+                self.params.extend(params.iter().map(|p| p.data.as_str()));
+                self.visit_expr(body.as_deref());
+                self.params.truncate(len);
+            }
+            _ => walk_expr(self, *node),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Compact S-expression rendering of the AST, without spans or comments, for golden-file tests.
+//! Statements of blocks are printed on their own lines, everything else on a single line. Scalar
+//! literals, which the parser wraps into 1x1 matrices, are printed bare.
+use crate::ast::{Expr, Statement};
+use crate::node::Node;
+use crate::value::Matrix;
+
+/// S-expression of a statement, such as `(= x (+ 1 2))`.
+pub fn stmt_to_sexp(stmt: &Statement) -> String {
+    let mut out = String::new();
+    write_stmt(&mut out, stmt, 0);
+    out
+}
+
+/// S-expression of an expression, such as `(call f 1 "a")`.
+pub fn expr_to_sexp(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr);
+    out
+}
+
+fn write_stmt(out: &mut String, stmt: &Statement, indent: usize) {
+    match stmt {
+        Statement::Error(s) => out.push_str(&format!("(error {:?})", s)),
+        Statement::IgnoreOutput(s) => {
+            out.push_str("(quiet ");
+            write_stmt(out, s, indent);
+            out.push(')');
+        }
+        Statement::Expr(e) => write_expr(out, e),
+        Statement::Assignment(t, e) => {
+            out.push_str("(= ");
+            write_expr(out, t);
+            out.push(' ');
+            write_expr(out, e);
+            out.push(')');
+        }
+        Statement::AugAssignment(name, op, e) => {
+            out.push_str(&format!("({}= {} ", op.symbol(), name));
+            write_expr(out, e);
+            out.push(')');
+        }
+        Statement::Block(v) => {
+            out.push_str("(block");
+            for s in v {
+                out.push('\n');
+                out.push_str(&"  ".repeat(indent + 1));
+                write_stmt(out, s, indent + 1);
+            }
+            out.push(')');
+        }
+        Statement::If(branches, otherwise) => {
+            out.push_str("(if");
+            for (c, b) in branches {
+                out.push_str(" (");
+                write_expr(out, c);
+                out.push(' ');
+                write_stmt(out, b, indent);
+                out.push(')');
+            }
+            if let Some(b) = otherwise {
+                out.push_str(" (else ");
+                write_stmt(out, b, indent);
+                out.push(')');
+            }
+            out.push(')');
+        }
+        Statement::Switch(e, cases, otherwise) => {
+            out.push_str("(switch ");
+            write_expr(out, e);
+            for (c, b) in cases {
+                out.push_str(" (case ");
+                write_expr(out, c);
+                out.push(' ');
+                write_stmt(out, b, indent);
+                out.push(')');
+            }
+            if let Some(b) = otherwise {
+                out.push_str(" (otherwise ");
+                write_stmt(out, b, indent);
+                out.push(')');
+            }
+            out.push(')');
+        }
+        Statement::Try(b, err, c) => {
+            out.push_str("(try ");
+            write_stmt(out, b, indent);
+            if err.is_some() || c.is_some() {
+                out.push_str(" (catch");
+                if let Some(err) = err {
+                    out.push(' ');
+                    out.push_str(err);
+                }
+                if let Some(c) = c {
+                    out.push(' ');
+                    write_stmt(out, c, indent);
+                }
+                out.push(')');
+            }
+            out.push(')');
+        }
+        Statement::UnwindProtect(b, c) => {
+            out.push_str("(unwind_protect ");
+            write_stmt(out, b, indent);
+            out.push(' ');
+            write_stmt(out, c, indent);
+            out.push(')');
+        }
+        Statement::Function(outputs, name, inputs, b) => {
+            out.push_str(&format!(
+                "(function {} ({}) ({}) ",
+                name.data,
+                names(outputs),
+                names(inputs)
+            ));
+            write_stmt(out, b, indent);
+            out.push(')');
+        }
+        Statement::For(i, e, b) => {
+            out.push_str(&format!("(for {} ", i));
+            write_expr(out, e);
+            out.push(' ');
+            write_stmt(out, b, indent);
+            out.push(')');
+        }
+        Statement::While(e, b) => {
+            out.push_str("(while ");
+            write_expr(out, e);
+            out.push(' ');
+            write_stmt(out, b, indent);
+            out.push(')');
+        }
+        Statement::DoUntil(b, e) => {
+            out.push_str("(do ");
+            write_stmt(out, b, indent);
+            out.push(' ');
+            write_expr(out, e);
+            out.push(')');
+        }
+        Statement::Break => out.push_str("break"),
+        Statement::Continue => out.push_str("continue"),
+        Statement::EOI => out.push_str("eoi"),
+    }
+}
+
+fn write_expr(out: &mut String, expr: &Expr) {
+    match expr {
+        Expr::Error(s) => out.push_str(&format!("(error {:?})", s)),
+        Expr::LitString(s) => out.push_str(&format!("{:?}", s.value)),
+        Expr::LitNumber(n) => out.push_str(&n.value.to_string()),
+        Expr::LitComplex(n) => out.push_str(&format!("{}i", n.value)),
+        Expr::LitInteger(n) => out.push_str(&format!("({} {})", n.ty, n.value)),
+        Expr::Identifier(name) => out.push_str(name),
+        Expr::Matrix(m) if m.len() == 1 && is_literal(&m.data[0]) => write_expr(out, &m.data[0]),
+        Expr::Matrix(m) => write_matrix(out, "matrix", m),
+        Expr::Cell(m) => write_matrix(out, "cell", m),
+        Expr::Op(op, a, b) => write_list(out, op.symbol(), &[a, b]),
+        Expr::Unary(op, e) => write_list(out, op.symbol(), &[e]),
+        Expr::Incr(e) => write_list(out, "post++", &[e]),
+        Expr::Decr(e) => write_list(out, "post--", &[e]),
+        Expr::Range(s, Some(st), e) => write_list(out, "range", &[s, st, e]),
+        Expr::Range(s, None, e) => write_list(out, "range", &[s, e]),
+        Expr::Call(c, args) => write_call(out, "call", c, args),
+        Expr::Command(c, args) => write_call(out, "command", c, args),
+        Expr::Index(c, args) => write_call(out, "index", c, args),
+        Expr::CellIndex(c, args) => write_call(out, "cell-index", c, args),
+        Expr::AnonFunction(params, body) => {
+            out.push_str(&format!("(lambda ({}) ", names(params)));
+            write_expr(out, body);
+            out.push(')');
+        }
+        Expr::Handle(name) => out.push_str(&format!("(handle {})", name.data)),
+        Expr::Colon => out.push(':'),
+        Expr::End => out.push_str("end"),
+    }
+}
+
+fn write_list(out: &mut String, head: &str, items: &[&Node<Box<Expr>>]) {
+    out.push('(');
+    out.push_str(head);
+    for e in items {
+        out.push(' ');
+        write_expr(out, e);
+    }
+    out.push(')');
+}
+
+fn write_call(out: &mut String, head: &str, callee: &Expr, args: &[Node<Expr>]) {
+    out.push('(');
+    out.push_str(head);
+    out.push(' ');
+    write_expr(out, callee);
+    for a in args {
+        out.push(' ');
+        write_expr(out, a);
+    }
+    out.push(')');
+}
+
+fn write_matrix(out: &mut String, head: &str, m: &Matrix<Node<Expr>>) {
+    out.push('(');
+    out.push_str(head);
+    for i in 0..m.height() {
+        out.push_str(" (row");
+        for e in &m[i] {
+            out.push(' ');
+            write_expr(out, e);
+        }
+        out.push(')');
+    }
+    out.push(')');
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::LitNumber(_) | Expr::LitComplex(_) | Expr::LitInteger(_) | Expr::LitString(_)
+    )
+}
+
+fn names(names: &[Node<String>]) -> String {
+    names
+        .iter()
+        .map(|n| n.data.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stmt_to_sexp;
+    use crate::parser::parse;
+
+    #[test]
+    fn golden() {
+        let actual = parse(
+            "x = [1 2; 3 4]';\n\
+             function y = f(a, b)\n  y = a(end, :) .* b{1} + 0x1Fu8;\nend\n\
+             if x == 1, disp('one'), elseif ~x, s.name = @(t) t^2; else z = 1:2:10; end\n\
+             try\n  error(\"no\");\ncatch err\n  for i = x, break, end\nend\n",
+        );
+        assert_eq!(
+            "(block
+  (quiet (= x (' (matrix (row 1 2) (row 3 4)))))
+  (function f (y) (a b) (block
+    (quiet (= y (+ (.* (index a end :) (cell-index b 1)) (uint8 31))))))
+  (if ((== x 1) (block
+    (call disp \"one\"))) ((~ x) (block
+    (quiet (= (. s name) (lambda (t) (^ t 2)))))) (else (block
+    (quiet (= z (range 1 2 10))))))
+  (try (block
+    (quiet (call error \"no\"))) (catch err (block
+    (for i x (block
+      break)))))
+  eoi)",
+            stmt_to_sexp(&actual)
+        );
+    }
+}
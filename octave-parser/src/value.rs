@@ -1,6 +1,7 @@
 use std::ops::{Index, IndexMut, Deref};
 
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Matrix<T> {
     pub(crate) data: Vec<T>,
     width: usize,
@@ -8,7 +9,7 @@ pub struct Matrix<T> {
 
 impl<T> Matrix<T> {
     pub fn from_vecs(data: Vec<Vec<T>>) -> Self {
-        let len = data[0].len();
+        let len = data.first().map_or(0, Vec::len);
         Self {
             data: data.into_iter().flat_map(|v| v.into_iter()).collect(),
             width: len,
@@ -20,7 +21,7 @@ impl<T> Matrix<T> {
     }
 
     pub fn height(&self) -> usize {
-        self.data.len() / self.width
+        self.data.len().checked_div(self.width).unwrap_or(0)
     }
 
     pub fn ix(&self, i: usize, j: usize) -> usize {
@@ -0,0 +1,274 @@
+//! Traversal of the AST. Implementors of `Visitor` and `VisitorMut` override the methods for the
+//! nodes they're interested in, and call the matching `walk_*` function to keep going down into
+//! their children. Children are always visited in source order.
+use crate::ast::{Expr, Statement};
+use crate::node::{Comment, Node, Position};
+use std::borrow::BorrowMut;
+use std::ops::Range;
+
+pub trait Visitor<'a> {
+    fn visit_stmt(&mut self, node: Node<&'a Statement>) {
+        walk_stmt(self, node.data)
+    }
+
+    fn visit_expr(&mut self, node: Node<&'a Expr>) {
+        walk_expr(self, node.data)
+    }
+
+    /// Names held by the tree outside of expressions: function names, inputs and outputs,
+    /// anonymous function parameters, caught errors and function handles.
+    fn visit_name(&mut self, _node: Node<&'a String>) {}
+}
+
+pub trait VisitorMut {
+    fn visit_stmt_mut(&mut self, stmt: &mut Statement) {
+        walk_stmt_mut(self, stmt)
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr)
+    }
+
+    /// See `Visitor::visit_name`.
+    fn visit_name_mut(&mut self, _node: &mut Node<String>) {}
+
+    /// Span and comments of every statement and expression under the visited ones, visited right
+    /// before the statement or expression itself.
+    fn visit_span_mut(&mut self, _span: &mut Range<Position>, _comments: &mut [Comment]) {}
+}
+
+/// Visits the statements, expressions and names directly under a statement.
+pub fn walk_stmt<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, stmt: &'a Statement) {
+    match stmt {
+        Statement::IgnoreOutput(s) => visitor.visit_stmt(s.as_deref()),
+        Statement::Expr(e) | Statement::AugAssignment(_, _, e) => visitor.visit_expr(e.as_ref()),
+        Statement::Assignment(t, e) => {
+            visitor.visit_expr(t.as_ref());
+            visitor.visit_expr(e.as_ref());
+        }
+        Statement::Block(v) => {
+            for s in v {
+                visitor.visit_stmt(s.as_ref());
+            }
+        }
+        Statement::If(branches, otherwise) => {
+            for (c, b) in branches {
+                visitor.visit_expr(c.as_ref());
+                visitor.visit_stmt(b.as_ref());
+            }
+            if let Some(b) = otherwise {
+                visitor.visit_stmt(b.as_deref());
+            }
+        }
+        Statement::Switch(e, cases, otherwise) => {
+            visitor.visit_expr(e.as_ref());
+            for (c, b) in cases {
+                visitor.visit_expr(c.as_ref());
+                visitor.visit_stmt(b.as_ref());
+            }
+            if let Some(b) = otherwise {
+                visitor.visit_stmt(b.as_deref());
+            }
+        }
+        Statement::Try(b, err, c) => {
+            visitor.visit_stmt(b.as_deref());
+            if let Some(err) = err {
+                visitor.visit_name(err.as_ref());
+            }
+            if let Some(c) = c {
+                visitor.visit_stmt(c.as_deref());
+            }
+        }
+        Statement::UnwindProtect(b, c) => {
+            visitor.visit_stmt(b.as_deref());
+            visitor.visit_stmt(c.as_deref());
+        }
+        Statement::Function(outputs, name, inputs, b) => {
+            for n in outputs.iter().chain(std::iter::once(name)).chain(inputs) {
+                visitor.visit_name(n.as_ref());
+            }
+            visitor.visit_stmt(b.as_deref());
+        }
+        Statement::For(_, e, b) | Statement::While(e, b) => {
+            visitor.visit_expr(e.as_ref());
+            visitor.visit_stmt(b.as_deref());
+        }
+        Statement::DoUntil(b, e) => {
+            visitor.visit_stmt(b.as_deref());
+            visitor.visit_expr(e.as_ref());
+        }
+        Statement::Error(_) | Statement::Break | Statement::Continue | Statement::EOI => {}
+    }
+}
+
+/// Visits the expressions and names directly under an expression.
+pub fn walk_expr<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, expr: &'a Expr) {
+    match expr {
+        Expr::Matrix(m) | Expr::Cell(m) => {
+            for n in m.iter() {
+                visitor.visit_expr(n.as_ref());
+            }
+        }
+        Expr::Op(_, a, b) => {
+            visitor.visit_expr(a.as_deref());
+            visitor.visit_expr(b.as_deref());
+        }
+        Expr::Unary(_, e) | Expr::Incr(e) | Expr::Decr(e) => visitor.visit_expr(e.as_deref()),
+        Expr::Range(s, st, e) => {
+            visitor.visit_expr(s.as_deref());
+            if let Some(st) = st {
+                visitor.visit_expr(st.as_deref());
+            }
+            visitor.visit_expr(e.as_deref());
+        }
+        Expr::Call(c, args)
+        | Expr::Command(c, args)
+        | Expr::Index(c, args)
+        | Expr::CellIndex(c, args) => {
+            visitor.visit_expr(c.as_deref());
+            for a in args {
+                visitor.visit_expr(a.as_ref());
+            }
+        }
+        Expr::AnonFunction(params, body) => {
+            for p in params {
+                visitor.visit_name(p.as_ref());
+            }
+            visitor.visit_expr(body.as_deref());
+        }
+        Expr::Handle(name) => visitor.visit_name(name.as_ref()),
+        Expr::Error(_)
+        | Expr::LitString(_)
+        | Expr::LitNumber(_)
+        | Expr::LitComplex(_)
+        | Expr::LitInteger(_)
+        | Expr::Identifier(_)
+        | Expr::Colon
+        | Expr::End => {}
+    }
+}
+
+/// Visits the statements, expressions and names directly under a statement.
+pub fn walk_stmt_mut<V: VisitorMut + ?Sized>(visitor: &mut V, stmt: &mut Statement) {
+    match stmt {
+        Statement::IgnoreOutput(s) => stmt_mut(visitor, s),
+        Statement::Expr(e) | Statement::AugAssignment(_, _, e) => expr_mut(visitor, e),
+        Statement::Assignment(t, e) => {
+            expr_mut(visitor, t);
+            expr_mut(visitor, e);
+        }
+        Statement::Block(v) => {
+            for s in v {
+                stmt_mut(visitor, s);
+            }
+        }
+        Statement::If(branches, otherwise) => {
+            for (c, b) in branches {
+                expr_mut(visitor, c);
+                stmt_mut(visitor, b);
+            }
+            if let Some(b) = otherwise {
+                stmt_mut(visitor, b);
+            }
+        }
+        Statement::Switch(e, cases, otherwise) => {
+            expr_mut(visitor, e);
+            for (c, b) in cases {
+                expr_mut(visitor, c);
+                stmt_mut(visitor, b);
+            }
+            if let Some(b) = otherwise {
+                stmt_mut(visitor, b);
+            }
+        }
+        Statement::Try(b, err, c) => {
+            stmt_mut(visitor, b);
+            if let Some(err) = err {
+                visitor.visit_name_mut(err);
+            }
+            if let Some(c) = c {
+                stmt_mut(visitor, c);
+            }
+        }
+        Statement::UnwindProtect(b, c) => {
+            stmt_mut(visitor, b);
+            stmt_mut(visitor, c);
+        }
+        Statement::Function(outputs, name, inputs, b) => {
+            for n in outputs
+                .iter_mut()
+                .chain(std::iter::once(name))
+                .chain(inputs)
+            {
+                visitor.visit_name_mut(n);
+            }
+            stmt_mut(visitor, b);
+        }
+        Statement::For(_, e, b) | Statement::While(e, b) => {
+            expr_mut(visitor, e);
+            stmt_mut(visitor, b);
+        }
+        Statement::DoUntil(b, e) => {
+            stmt_mut(visitor, b);
+            expr_mut(visitor, e);
+        }
+        Statement::Error(_) | Statement::Break | Statement::Continue | Statement::EOI => {}
+    }
+}
+
+/// Visits the expressions and names directly under an expression.
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::Matrix(m) | Expr::Cell(m) => {
+            for n in m.data.iter_mut() {
+                expr_mut(visitor, n);
+            }
+        }
+        Expr::Op(_, a, b) => {
+            expr_mut(visitor, a);
+            expr_mut(visitor, b);
+        }
+        Expr::Unary(_, e) | Expr::Incr(e) | Expr::Decr(e) => expr_mut(visitor, e),
+        Expr::Range(s, st, e) => {
+            expr_mut(visitor, s);
+            if let Some(st) = st {
+                expr_mut(visitor, st);
+            }
+            expr_mut(visitor, e);
+        }
+        Expr::Call(c, args)
+        | Expr::Command(c, args)
+        | Expr::Index(c, args)
+        | Expr::CellIndex(c, args) => {
+            expr_mut(visitor, c);
+            for a in args {
+                expr_mut(visitor, a);
+            }
+        }
+        Expr::AnonFunction(params, body) => {
+            for p in params {
+                visitor.visit_name_mut(p);
+            }
+            expr_mut(visitor, body);
+        }
+        Expr::Handle(name) => visitor.visit_name_mut(name),
+        Expr::Error(_)
+        | Expr::LitString(_)
+        | Expr::LitNumber(_)
+        | Expr::LitComplex(_)
+        | Expr::LitInteger(_)
+        | Expr::Identifier(_)
+        | Expr::Colon
+        | Expr::End => {}
+    }
+}
+
+fn stmt_mut<V: VisitorMut + ?Sized, S: BorrowMut<Statement>>(visitor: &mut V, node: &mut Node<S>) {
+    visitor.visit_span_mut(&mut node.span, &mut node.comments);
+    visitor.visit_stmt_mut(node.data.borrow_mut());
+}
+
+fn expr_mut<V: VisitorMut + ?Sized, E: BorrowMut<Expr>>(visitor: &mut V, node: &mut Node<E>) {
+    visitor.visit_span_mut(&mut node.span, &mut node.comments);
+    visitor.visit_expr_mut(node.data.borrow_mut());
+}
@@ -1,19 +1,98 @@
 use std::fmt;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum IntegerType {
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
+}
+
+impl IntegerType {
+    /// Integer type with the given signedness and number of bits, if it exists.
+    pub fn new(signed: bool, bits: u32) -> Option<Self> {
+        Some(match (signed, bits) {
+            (true, 8) => Self::Int8,
+            (true, 16) => Self::Int16,
+            (true, 32) => Self::Int32,
+            (true, 64) => Self::Int64,
+            (false, 8) => Self::UInt8,
+            (false, 16) => Self::UInt16,
+            (false, 32) => Self::UInt32,
+            (false, 64) => Self::UInt64,
+            _ => return None,
+        })
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Self::Int8 | Self::Int16 | Self::Int32 | Self::Int64)
+    }
+
+    pub fn bits(&self) -> u32 {
+        match self {
+            Self::Int8 | Self::UInt8 => 8,
+            Self::Int16 | Self::UInt16 => 16,
+            Self::Int32 | Self::UInt32 => 32,
+            Self::Int64 | Self::UInt64 => 64,
+        }
+    }
+}
+
+impl fmt::Display for IntegerType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = if self.is_signed() { "int" } else { "uint" };
+        write!(f, "{}{}", prefix, self.bits())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum SimpleType {
     Void,
     Single,
     Double,
+    /// Complex double
+    Complex,
+    Integer(IntegerType),
+    Logical,
     String,
     Unknown,
 }
 
 impl SimpleType {
     pub fn is_scalar(&self) -> bool {
-        match self {
-            Self::Single | Self::Double => true,
-            _ => false,
+        matches!(
+            self,
+            Self::Single | Self::Double | Self::Complex | Self::Integer(_) | Self::Logical
+        )
+    }
+
+    /// Element type resulting from concatenating values of both types.
+    pub fn promote(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Self::Unknown, _) | (_, Self::Unknown) => Self::Unknown,
+            // Integers can't be mixed with complex numbers
+            (Self::Integer(_), Self::Complex) | (Self::Complex, Self::Integer(_)) => Self::Unknown,
+            // Mixing integer types keeps the leftmost one
+            (Self::Integer(i), _) | (_, Self::Integer(i)) => Self::Integer(i),
+            (Self::Complex, _) | (_, Self::Complex) => Self::Complex,
+            (Self::String, _) | (_, Self::String) => Self::String,
+            (Self::Single, _) | (_, Self::Single) => Self::Single,
+            _ => Self::Double,
+        }
+    }
+
+    /// Element type resulting from an arithmetic operation between values of both types.
+    pub fn arithmetic(self, other: Self) -> Self {
+        match self.promote(other) {
+            Self::String | Self::Logical => Self::Double,
+            ty => ty,
         }
     }
 }
@@ -24,6 +103,9 @@ impl fmt::Display for SimpleType {
             Self::Void => write!(f, "void"),
             Self::Single => write!(f, "single"),
             Self::Double => write!(f, "double"),
+            Self::Complex => write!(f, "complex"),
+            Self::Integer(i) => i.fmt(f),
+            Self::Logical => write!(f, "logical"),
             Self::String => write!(f, "string"),
             Self::Unknown => write!(f, "?"),
         }
@@ -31,6 +113,7 @@ impl fmt::Display for SimpleType {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct CallableType {
     pub args_types: Vec<Type>,
     pub return_type: Box<Type>,
@@ -58,6 +141,90 @@ impl fmt::Display for CallableType {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CellType {
+    pub size: Option<(usize, usize)>,
+    /// Types of the elements in row-major order, when known
+    pub elements: Option<Vec<Type>>,
+}
+
+impl CellType {
+    pub fn unknown() -> Self {
+        Self {
+            size: None,
+            elements: None,
+        }
+    }
+
+    /// Type of the element at the given zero-based position, when known.
+    pub fn get(&self, row: usize, col: usize) -> Option<&Type> {
+        let (rows, cols) = self.size?;
+        if row < rows && col < cols {
+            self.elements.as_ref()?.get(row * cols + col)
+        } else {
+            None
+        }
+    }
+
+    /// Type common to all elements of the cell array, if there is one.
+    pub fn common_type(&self) -> Option<&Type> {
+        let elements = self.elements.as_ref()?;
+        let first = elements.first()?;
+        if elements.iter().all(|t| t == first) {
+            Some(first)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for CellType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.size {
+            Some((rows, cols)) => write!(f, "{}x{} cell", rows, cols),
+            None => write!(f, "cell"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StructType {
+    /// Fields in the order they were first assigned
+    pub fields: Vec<(String, Type)>,
+}
+
+impl StructType {
+    pub fn field(&self, name: &str) -> Option<&Type> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, t)| t)
+    }
+
+    /// Sets the type of a field, adding it if it doesn't exist yet.
+    pub fn set_field(&mut self, name: String, ty: Type) {
+        match self.fields.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, t)) => *t = ty,
+            None => self.fields.push((name, ty)),
+        }
+    }
+}
+
+impl fmt::Display for StructType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "struct {{")?;
+        let mut first = true;
+        for (name, _) in &self.fields {
+            if !first {
+                write!(f, ",")?;
+            }
+            write!(f, " {}", name)?;
+            first = false;
+        }
+        write!(f, " }}")
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Type {
     SimpleType(SimpleType),
     Matrix {
@@ -65,6 +232,8 @@ pub enum Type {
         ty: SimpleType,
     },
     Callable(CallableType),
+    Cell(CellType),
+    Struct(StructType),
     Unknown,
 }
 
@@ -75,6 +244,8 @@ impl fmt::Display for Type {
             Self::Matrix { size: Some((rows, cols)), ty } => write!(f, "{}x{} {} matrix", rows, cols, ty),
             Self::Matrix { ty , ..} => write!(f, "{} matrix", ty),
             Self::Callable(c) => c.fmt(f),
+            Self::Cell(c) => c.fmt(f),
+            Self::Struct(s) => s.fmt(f),
             Self::Unknown => write!(f, "?"),
         }
     }
@@ -89,9 +260,55 @@ impl Type {
         }
     }
 
+    /// Size as `(rows, columns)`, when known.
+    pub fn size(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::SimpleType(SimpleType::String) => None,
+            Self::SimpleType(SimpleType::Void) => Some((0, 0)),
+            Self::SimpleType(_) => Some((1, 1)),
+            Self::Matrix { size, .. } | Self::Cell(CellType { size, .. }) => *size,
+            _ => None,
+        }
+    }
+
+    /// Type of the elements of a value of this type.
+    pub fn element_type(&self) -> SimpleType {
+        match self {
+            Self::SimpleType(s) | Self::Matrix { ty: s, .. } => *s,
+            _ => SimpleType::Unknown,
+        }
+    }
+
+    /// Type of a `for` loop variable iterating over this type, i.e. one of its columns.
+    pub fn column(&self) -> Type {
+        match self {
+            Self::Matrix {
+                size: Some((rows, _)),
+                ty,
+            } => Self::Matrix {
+                size: Some((*rows, 1)),
+                ty: *ty,
+            },
+            Self::Matrix { ty, .. } => Self::Matrix {
+                size: None,
+                ty: *ty,
+            },
+            Self::SimpleType(s) => Self::SimpleType(*s),
+            Self::Cell(c) => Self::Cell(CellType {
+                size: c.size.map(|(rows, _)| (rows, 1)),
+                elements: None,
+            }),
+            _ => Self::Unknown,
+        }
+    }
+
     pub fn simple_type(&self) -> Option<SimpleType> {
         match self {
             Self::SimpleType(s) => Some(*s),
+            Self::Matrix {
+                size: Some((1, 1)),
+                ty,
+            } => Some(*ty),
             Self::Callable(c) if c.is_scalar() => c.return_type.simple_type(),
             _ => None,
         }